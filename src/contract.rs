@@ -1,13 +1,23 @@
-
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::ops::Add;
-use std::vec::IntoIter;
-use cosmwasm_std::{debug_print, to_binary, Api, Binary, Env, Extern, HandleResponse, InitResponse, Querier, StdError, StdResult, Storage, HumanAddr, CosmosMsg, Coin, Uint128, BankMsg, from_binary, LogAttribute};
+use cosmwasm_std::{from_binary, plaintext_log, to_binary, Api, Binary, BlockInfo, Env, Extern, HandleResponse, InitResponse, Querier, StdError, StdResult, Storage, HumanAddr, CosmosMsg, Coin, Uint128, BankMsg};
 use secret_toolkit::permit::{Permit, validate};
-use secret_toolkit::snip721::{AccessLevel, Metadata, nft_dossier_query, NftDossier, register_receive_nft_msg, set_viewing_key_msg, set_whitelisted_approval_msg, tokens_query, Trait, transfer_nft_msg, ViewerInfo};
+use secret_toolkit::snip20::{register_receive_msg, transfer_msg};
+use secret_toolkit::utils::Query;
+use secret_toolkit::snip721::{AccessLevel, Metadata, nft_dossier_query, NftDossier, register_receive_nft_msg, set_viewing_key_msg, set_whitelisted_approval_msg, tokens_query, transfer_nft_msg, ViewerInfo, Expiration, batch_transfer_nft_msg, Transfer};
 use snafu::{Backtrace, GenerateBacktrace};
 
-use crate::msg::{ConfigResponse, HandleMsg, InitMsg, NftResponse, QueryMsg};
-use crate::state::{config, config_read, PREFIX_PERMITS, State, store_read, store_remove, store_set, StoreNftInfo, SUFFIX_ED_KEY, SUFFIX_IP_KEY};
+use crate::gating::{Evaluator, GatingRule, IpToken};
+use crate::msg::{BatchNftDossierResponse, BidResponse, ConfigResponse, HandleMsg, InitMsg, Listing, ListingMsg, OffersResponse, TokenOffer, ListingsResponse, NftResponse, QueryMsg, ReceiveMsg, DisplayRoyaltyInfo, RoyaltyInfoResponse, SaleFilter, SaleHistoryResponse, Snip721QueryMsg};
+use crate::state::{AccessGrant, add_coin, block_time_read, block_time_save, mul_ratio, grant_read, grant_remove, grant_save, Bundle, bundle_create, bundle_of, bundle_read, bundle_remove, config, config_read, listed_tokens, PREFIX_PERMITS, record_sale, sales_read, SaleRecord, seller_tokens, fees_add, fees_clear, fees_read, Snip20Token, State, store_read, store_remove, store_set, StoreNftInfo, Bid, SaleKind, sealed_bids_read, sealed_bids_save, default_ip_scan_limit, Offer, offer_remove, offer_set, offered_tokens, offers_read, SUFFIX_ED_KEY, SUFFIX_IP_KEY, validate_price};
+
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+/// IP tokens requested per page while checking the gating rule
+const IP_PAGE_SIZE: u32 = 100;
+/// seconds an access grant recorded by ClaimAccess lasts
+const ACCESS_GRANT_SECS: u64 = 24*60*60;
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
@@ -21,19 +31,29 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
         ip_code_hash: msg.ip_code_hash,
         owner: deps.api.canonical_address(&env.message.sender)?,
         contract_addr: env.contract.address.clone(),
-        viewing_key: msg.view_key
+        viewing_key: msg.view_key,
+        payment_tokens: msg.payment_tokens.unwrap_or_default(),
+        fee_bps: check_fee(msg.fee_bps.unwrap_or(0))?,
+        treasury: deps.api.canonical_address(msg.treasury.as_ref().unwrap_or(&env.message.sender))?,
+        gating_rule: msg.gating_rule.unwrap_or_default(),
+        ip_scan_limit: default_ip_scan_limit(),
     };
 
-    let res_msg=vec![
+    let mut res_msg=vec![
         set_viewing_key_msg(state.viewing_key.clone().add(SUFFIX_IP_KEY), None, 256,
                             state.ip_code_hash.to_owned(), deps.api.human_address(&state.ip_nft_contract)?)?,
         set_viewing_key_msg(state.viewing_key.clone().add(SUFFIX_ED_KEY), None, 256,
                             state.ed_code_hash.to_owned(), deps.api.human_address(&state.ed_nft_contract)?)?,
-        register_receive_nft_msg(env.contract_code_hash, None, None,
+        register_receive_nft_msg(env.contract_code_hash.clone(), Some(true), None,
                                  256, state.ed_code_hash.to_owned(), deps.api.human_address(&state.ed_nft_contract)?)?];
+    for token in &state.payment_tokens {
+        res_msg.push(register_receive_msg(env.contract_code_hash.clone(), None, 256,
+                                          token.code_hash.to_owned(), token.address.to_owned())?);
+    }
 
 
     config(&mut deps.storage).save(&state)?;
+    block_time_save(&mut deps.storage, env.block.time)?;
 
     Ok(InitResponse{ messages: res_msg, log: vec![] })
 }
@@ -43,63 +63,921 @@ pub fn handle<S: Storage, A: Api, Q: Querier>(
     env: Env,
     msg: HandleMsg,
 ) -> StdResult<HandleResponse> {
+    block_time_save(&mut deps.storage, env.block.time)?;
     match msg {
         HandleMsg::ReceiveNft { sender,token_id,msg } =>
-            set_sender_auth(deps, sender, &token_id, msg),
+            set_sender_auth(deps, env, sender, &token_id, msg),
+        HandleMsg::BatchReceiveNft { from,token_ids,msg,.. } =>
+            batch_list(deps, env, from, token_ids, msg),
         HandleMsg::Reset { view_key } => set_up(deps, env,view_key),
-        HandleMsg::Transfer {token_id,receipient}=>buy(deps,env,&token_id,receipient)
+        HandleMsg::Transfer {token_id,receipient}=>buy(deps,env,&token_id,receipient),
+        HandleMsg::BatchTransfer {token_ids,recipient}=>batch_buy(deps,env,token_ids,recipient),
+        HandleMsg::CancelListing {token_id}=>cancel_listing(deps,env,&token_id),
+        HandleMsg::UpdateListing {token_id,price,payout}=>update_listing(deps,env,&token_id,price,payout),
+        HandleMsg::Receive {from,amount,msg,..}=>receive(deps,env,from,amount,msg),
+        HandleMsg::AddPaymentToken {token}=>add_payment_token(deps,env,token),
+        HandleMsg::SetFee {fee_bps,treasury}=>set_fee(deps,env,fee_bps,treasury),
+        HandleMsg::WithdrawFees {}=>withdraw_fees(deps,env),
+        HandleMsg::SetGatingRule {rule}=>set_gating_rule(deps,env,rule),
+        HandleMsg::SetScanLimit {limit}=>set_scan_limit(deps,env,limit),
+        HandleMsg::ClaimAccess {token_id}=>claim_access(deps,env,&token_id),
+        HandleMsg::Bid {token_id}=>bid(deps,env,&token_id),
+        HandleMsg::Finalize {token_id}=>finalize(deps,env,&token_id),
+        HandleMsg::CreateBundle {token_ids,price,payout}=>create_bundle(deps,env,token_ids,price,payout),
+        HandleMsg::CancelBundle {bundle_id}=>cancel_bundle(deps,env,bundle_id),
+        HandleMsg::BuyBundle {bundle_id,recipient}=>buy_bundle(deps,env,bundle_id,recipient),
+        HandleMsg::ReclaimExpired {token_id}=>reclaim_expired(deps,env,&token_id),
+        HandleMsg::MakeOffer {token_id,expires}=>make_offer(deps,env,&token_id,expires),
+        HandleMsg::CancelOffer {token_id}=>cancel_offer(deps,env,&token_id),
+        HandleMsg::AcceptOffer {token_id,buyer}=>accept_listed_offer(deps,env,&token_id,buyer),
     }
 }
 
 pub fn set_sender_auth<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
+    env: Env,
     sender: HumanAddr,
     token_id: &String,
     msg: Option<Binary>, )->StdResult<HandleResponse>{
     let config=config_read(&deps.storage).load()?;
+    //only the ED contract may call back, after it moved the token to this contract
+    if env.message.sender!=deps.api.human_address(&config.ed_nft_contract)? {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    check_held(deps, &env, &config, token_id)?;
 
-    let info = StoreNftInfo::from(msg.clone().unwrap_or_default())?;
-    let r=vec![set_whitelisted_approval_msg(sender, Option::from(token_id.clone()),
-                                            Option::from(AccessLevel::ApproveToken),
-                                            Option::from(AccessLevel::ApproveToken), None, None, None, 256,
-                                            config.ed_code_hash, deps.api.human_address(&config.ed_nft_contract)?)?];
+    if let Some(ListingMsg::AcceptOffer { buyer })=msg.as_ref().and_then(|m|from_binary(m).ok()) {
+        let info=StoreNftInfo{ owner: sender, price: Uint128::zero(), denom: String::new(),
+                               payout: None, expires_at: None, kind: SaleKind::Fixed };
+        return accept_offer(deps, env, token_id, info, buyer);
+    }
+    let info = StoreNftInfo::from_msg(msg, sender)?;
+    let r=vec![list_token(deps, &env, &config, token_id, &info)?];
 
-    // let valid_msg=info.is_ok();
-    // if valid_msg {
-        store_set(&mut deps.storage,token_id,&info)?;
-    // }
+    Ok(HandleResponse{
+        messages: r,
+        log: vec![
+            plaintext_log("action", "list"),
+            plaintext_log("token_id", token_id),
+            plaintext_log("price", info.price),
+            plaintext_log("denom", &info.denom),
+        ],
+        data: None })
+}
+
+/// lists every token of a batch send for `from`, applying the same `ListingMsg` to each
+pub fn batch_list<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    token_ids: Vec<String>,
+    msg: Option<Binary>, )->StdResult<HandleResponse>{
+    let config=config_read(&deps.storage).load()?;
+    if env.message.sender!=deps.api.human_address(&config.ed_nft_contract)? {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    let list_each=match msg.as_ref().and_then(|m|from_binary(m).ok()) {
+        Some(listing @ ListingMsg::ListEach { .. }) => Some(listing),
+        _ => None,
+    };
+    //a contract registered for batches delivers single sends here too, they are decoded like ReceiveNft
+    if let (None, [token_id])=(&list_each, token_ids.as_slice()) {
+        return set_sender_auth(deps, env, from, token_id, msg);
+    }
+
+    let mut r=vec![];
+    for token_id in &token_ids {
+        check_held(deps, &env, &config, token_id)?;
+        let info=match &list_each {
+            Some(listing) => StoreNftInfo::from_listing(listing.for_token(token_id)?, from.clone())?,
+            None => StoreNftInfo::from_msg(msg.clone(), from.clone())?,
+        };
+        r.push(list_token(deps, &env, &config, token_id, &info)?);
+    }
 
     Ok(HandleResponse{
         messages: r,
         log: vec![
-            LogAttribute{
-            key: "debugxx".to_string(),
-            value: String::from_utf8(msg.clone().unwrap_or_default().into()).unwrap_or("wrong".parse().unwrap()),
-            encrypted: false
-        // },LogAttribute{
-        //         key: "debug".to_string(),
-        //         value: valid_msg.to_string(),
-        //         encrypted: false
-            },
+            plaintext_log("action", "batch_list"),
+            plaintext_log("token_ids", token_ids.join(",")),
         ],
         data: None })
 }
 
+fn check_held<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, env: &Env, config: &State, token_id: &String) -> StdResult<()> {
+    let ednft=get_ed_nft(deps, token_id.clone(), config)?;
+    if ednft.owner.as_ref()!=Some(&env.contract.address) {
+        return Err(StdError::generic_err(format!("token {} is not held by this contract", token_id)));
+    }
+    Ok(())
+}
+
+/// stores the listing of an escrowed token, returning the approval that keeps its seller able to view it
+fn list_token<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    config: &State,
+    token_id: &String,
+    info: &StoreNftInfo,
+) -> StdResult<CosmosMsg> {
+    if let SaleKind::English { end_time, .. } | SaleKind::Sealed { end_time }=info.kind {
+        if end_time<=env.block.time {
+            return Err(StdError::generic_err(format!("auction end time {} has already passed", end_time)));
+        }
+    }
+    check_sealed_denom(config, info)?;
+    let msg=set_whitelisted_approval_msg(info.owner.clone(), Option::from(token_id.clone()),
+                                         Option::from(AccessLevel::ApproveToken),
+                                         Option::from(AccessLevel::ApproveToken), None, None, None, 256,
+                                         config.ed_code_hash.to_owned(), deps.api.human_address(&config.ed_nft_contract)?)?;
+    store_set(&mut deps.storage,token_id,info)?;
+    Ok(msg)
+}
+
 pub fn set_up<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    vkey:String,
+    vkey:String,
+) -> StdResult<HandleResponse> {
+    let api=&deps.api.clone();
+    let mut res_msg:Vec<CosmosMsg>=vec![];
+    config(&mut deps.storage).update(|mut state| {
+        if env.message.sender!=api.human_address(&state.owner)? { Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) }) }
+        else {
+            res_msg=vec![set_viewing_key_msg(vkey.clone().add(SUFFIX_IP_KEY), None, 256,
+                                state.ip_code_hash.to_owned(), api.human_address(&state.ip_nft_contract)?)?,
+            set_viewing_key_msg(vkey.clone().add(SUFFIX_ED_KEY), None, 256,
+                                state.ed_code_hash.to_owned(), api.human_address(&state.ed_nft_contract)?)?];
+            state.viewing_key=vkey;
+            Ok(state) }
+    })?;
+
+    Ok(HandleResponse{
+        messages: res_msg,
+        log: vec![],
+        data: None
+    })
+}
+
+pub fn buy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+    receipient:Option<HumanAddr>
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let sender=&env.message.sender;
+    let recipient=receipient.unwrap_or_else(||sender.to_owned());
+    //the contract owner may rescue a token the contract holds without a listing, listed tokens
+    //are only released by a sale or by their seller
+    if *sender==deps.api.human_address(&state.owner)?&&env.message.sent_funds.is_empty()&&store_read(&deps.storage,tokenid).is_err() {
+        let res=vec![transfer_nft_msg(recipient, tokenid.clone(), None, None, 256,
+                                      state.ed_code_hash.to_owned(),
+                                      deps.api.human_address(&state.ed_nft_contract)?)?];
+        return Ok(HandleResponse{
+            messages: res,
+            log: vec![plaintext_log("action", "rescue"), plaintext_log("token_id", tokenid)],
+            data: None
+        });
+    }
+
+    let mut info = store_read(&deps.storage,tokenid)?;
+    buy_price(&deps.storage, tokenid, &mut info, &env.block)?;
+    let paid = native_payment(&state, &env, &info)?;
+    let res = sell(deps, &env, tokenid, info, sender.to_owned(), recipient, paid)?;
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![],
+        data: None
+    })
+}
+
+/// buys every token of `tokenids` with the sent funds in one batch transfer,
+/// paying each seller and royalty recipient with a single send
+pub fn batch_buy<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenids: Vec<String>,
+    recipient: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let buyer=env.message.sender.clone();
+    if tokenids.is_empty() {
+        return Err(StdError::generic_err("no tokens to buy"));
+    }
+
+    let mut listings=vec![];
+    let mut excess=vec![];
+    for coin in &env.message.sent_funds {
+        add_coin(&mut excess, &coin.denom, coin.amount);
+    }
+    for tokenid in &tokenids {
+        if listings.iter().any(|(id, _)|*id==tokenid) {
+            return Err(StdError::generic_err(format!("token {} is requested twice", tokenid)));
+        }
+        let mut info=store_read(&deps.storage,tokenid)?;
+        buy_price(&deps.storage, tokenid, &mut info, &env.block)?;
+        if state.payment_tokens.iter().any(|t|t.address.as_str()==info.denom) {
+            return Err(StdError::generic_err(format!("token {} is priced in SNIP-20 {}, buy it with a Send of that token", tokenid, info.denom)));
+        }
+        let sent=excess.iter_mut().find(|c|c.denom==info.denom).filter(|c|c.amount>=info.price)
+            .ok_or_else(||StdError::generic_err(format!("not enough {} sent for token {}", info.denom, tokenid)))?;
+        sent.amount=(sent.amount-info.price)?;
+        listings.push((tokenid, info));
+    }
+    excess.retain(|c|!c.amount.is_zero());
+
+    let mut payouts=vec![];
+    for (tokenid, info) in listings {
+        settle_batched(deps, &state, &env, tokenid, info, &buyer, &mut payouts)?;
+    }
+
+    let mut res=vec![batch_transfer_nft_msg(vec![Transfer{ recipient: recipient.unwrap_or_else(||buyer.clone()), token_ids: tokenids, memo: None }],
+                                            None, 256, state.ed_code_hash.to_owned(),
+                                            deps.api.human_address(&state.ed_nft_contract)?)?];
+    if !excess.is_empty() {
+        payouts.push((buyer, excess));
+    }
+    res.extend(grouped_payment_msgs(&state, payouts)?);
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "batch_buy")],
+        data: None
+    })
+}
+
+pub fn create_bundle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenids: Vec<String>,
+    price: Coin,
+    payout: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    if tokenids.len()<2 {
+        return Err(StdError::generic_err("a bundle needs at least two tokens"));
+    }
+    for (i, tokenid) in tokenids.iter().enumerate() {
+        if tokenids[..i].contains(tokenid) {
+            return Err(StdError::generic_err(format!("token {} is requested twice", tokenid)));
+        }
+        let info=store_read(&deps.storage,tokenid)?;
+        if env.message.sender!=info.owner {
+            return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+        }
+        check_unbundled(&deps.storage, tokenid)?;
+        if info.kind!=SaleKind::Fixed {
+            return Err(StdError::generic_err(format!("token {} is up for auction and can not be bundled", tokenid)));
+        }
+    }
+
+    let bundle=Bundle{ seller: env.message.sender, token_ids: tokenids, price: validate_price(&price)?, denom: price.denom, payout };
+    let id=bundle_create(&mut deps.storage, &bundle)?;
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![plaintext_log("action", "create_bundle"), plaintext_log("bundle_id", id)],
+        data: Some(to_binary(&id)?)
+    })
+}
+
+pub fn cancel_bundle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    bundle_id: u64,
+) -> StdResult<HandleResponse> {
+    let bundle=bundle_read(&deps.storage,bundle_id)?;
+    if env.message.sender!=bundle.seller {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    bundle_remove(&mut deps.storage,bundle_id)?;
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![plaintext_log("action", "cancel_bundle"), plaintext_log("bundle_id", bundle_id)],
+        data: None
+    })
+}
+
+pub fn buy_bundle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    bundle_id: u64,
+    recipient: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let bundle=bundle_read(&deps.storage,bundle_id)?;
+    if state.payment_tokens.iter().any(|t|t.address.as_str()==bundle.denom) {
+        return Err(StdError::generic_err(format!("bundle is priced in SNIP-20 {}, pay with a Send of that token", bundle.denom)));
+    }
+    let paid=Coin{ denom: bundle.denom.clone(), amount: check_fund(&env.message.sent_funds, &bundle.denom)? };
+    let buyer=env.message.sender.clone();
+    let res=sell_bundle(deps, &env, bundle_id, bundle, buyer.clone(), recipient.unwrap_or(buyer), paid)?;
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "buy_bundle"), plaintext_log("bundle_id", bundle_id)],
+        data: None
+    })
+}
+
+/// moves all tokens of `bundle` to `recipient` in one batch transfer, refunding anything paid above its price.
+/// The price is split evenly over the tokens for their royalties and sale records, the first taking the remainder
+fn sell_bundle<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    bundle_id: u64,
+    bundle: Bundle,
+    buyer: HumanAddr,
+    recipient: HumanAddr,
+    paid: Coin,
+) -> StdResult<Vec<CosmosMsg>> {
+    let state=config_read(&deps.storage).load()?;
+    if paid.denom!=bundle.denom {
+        return Err(StdError::generic_err(format!("bundle is priced in {}, can not pay with {}", bundle.denom, paid.denom)));
+    }
+    if paid.amount<bundle.price {
+        return Err(StdError::generic_err(format!("bundle price is {}{}, received {}{}", bundle.price, bundle.denom, paid.amount, paid.denom)));
+    }
+    let excess=(paid.amount-bundle.price)?;
+
+    let count=bundle.token_ids.len() as u128;
+    let mut payouts=vec![];
+    bundle_remove(&mut deps.storage,bundle_id)?;
+    for (i, tokenid) in bundle.token_ids.iter().enumerate() {
+        let mut info=store_read(&deps.storage,tokenid)?;
+        if info.is_expired(&env.block) {
+            return Err(StdError::generic_err(format!("the listing of token {} expired", tokenid)));
+        }
+        let remainder=if i==0 { bundle.price.u128()%count } else { 0 };
+        info.price=Uint128(bundle.price.u128()/count+remainder);
+        info.denom=bundle.denom.clone();
+        info.payout=bundle.payout.clone();
+        settle_batched(deps, &state, env, tokenid, info, &buyer, &mut payouts)?;
+    }
+
+    let mut res=vec![batch_transfer_nft_msg(vec![Transfer{ recipient, token_ids: bundle.token_ids, memo: None }],
+                                            None, 256, state.ed_code_hash.to_owned(),
+                                            deps.api.human_address(&state.ed_nft_contract)?)?];
+    if !excess.is_zero() {
+        payouts.push((buyer, vec![Coin{ denom: bundle.denom, amount: excess }]));
+    }
+    res.extend(grouped_payment_msgs(&state, payouts)?);
+    Ok(res)
+}
+
+/// records the sale of `tokenid` at the price of `info` as part of a batch, adding its payouts to `payouts`
+fn settle_batched<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    state: &State,
+    env: &Env,
+    tokenid: &String,
+    info: StoreNftInfo,
+    buyer: &HumanAddr,
+    payouts: &mut Vec<(HumanAddr, Vec<Coin>)>,
+) -> StdResult<()> {
+    for (to, amount) in sale_payouts(deps, state, tokenid, info.price, &info.denom, info.payout_addr())? {
+        match payouts.iter_mut().find(|(addr, _)|*addr==to) {
+            Some((_, coins))=>add_coin(coins, &info.denom, amount),
+            None=>payouts.push((to, vec![Coin{ denom: info.denom.clone(), amount }])),
+        }
+    }
+    record_sale(&mut deps.storage, &SaleRecord{
+        token_id: tokenid.clone(),
+        seller: info.owner,
+        buyer: buyer.clone(),
+        price: info.price,
+        denom: info.denom,
+        block_height: env.block.height,
+        block_time: env.block.time,
+    })?;
+    store_remove(&mut deps.storage,tokenid)
+}
+
+/// one send of all native coins per recipient, SNIP-20 amounts are transferred separately
+fn grouped_payment_msgs(state: &State, payouts: Vec<(HumanAddr, Vec<Coin>)>) -> StdResult<Vec<CosmosMsg>> {
+    let mut res=vec![];
+    for (to, coins) in payouts {
+        let (tokens, native): (Vec<Coin>, Vec<Coin>)=coins.into_iter()
+            .partition(|c|state.payment_tokens.iter().any(|t|t.address.as_str()==c.denom));
+        if !native.is_empty() {
+            res.push(CosmosMsg::Bank(BankMsg::Send{ from_address: state.contract_addr.to_owned(), to_address: to.clone(), amount: native }));
+        }
+        for coin in tokens {
+            res.push(payment_msg(state, &coin.denom, to.clone(), coin.amount)?);
+        }
+    }
+    Ok(res)
+}
+
+/// SNIP-20 Send callback, paying for a listing priced in the sending token
+pub fn receive<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    from: HumanAddr,
+    amount: Uint128,
+    msg: Option<Binary>,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    if !state.payment_tokens.iter().any(|t|t.address==env.message.sender) {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    let msg=msg.ok_or_else(||StdError::generic_err("no receive msg provided"))?;
+    let paid=Coin{ denom: env.message.sender.to_string(), amount };
+    let res=match from_binary(&msg)? {
+        ReceiveMsg::Buy { token_id, recipient } => {
+            let mut info=store_read(&deps.storage,&token_id)?;
+            buy_price(&deps.storage, &token_id, &mut info, &env.block)?;
+            sell(deps, &env, &token_id, info, from.clone(), recipient.unwrap_or(from), paid)?
+        }
+        ReceiveMsg::BuyBundle { bundle_id, recipient } => {
+            let bundle=bundle_read(&deps.storage,bundle_id)?;
+            sell_bundle(deps, &env, bundle_id, bundle, from.clone(), recipient.unwrap_or(from), paid)?
+        }
+        ReceiveMsg::Bid { token_id } => {
+            let info=store_read(&deps.storage,&token_id)?;
+            place_bid(deps, &env, &token_id, info, from, paid)?
+        }
+    };
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![],
+        data: None
+    })
+}
+
+pub fn bid<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let info=store_read(&deps.storage,tokenid)?;
+    let paid=native_payment(&state, &env, &info)?;
+    let res=place_bid(deps, &env, tokenid, info, env.message.sender.to_owned(), paid)?;
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![],
+        data: None
+    })
+}
+
+/// escrows `paid` as the highest bid of an English auction, refunding the bid it replaces
+fn place_bid<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    tokenid: &String,
+    mut info: StoreNftInfo,
+    bidder: HumanAddr,
+    paid: Coin,
+) -> StdResult<Vec<CosmosMsg>> {
+    let state=config_read(&deps.storage).load()?;
+    if paid.denom!=info.denom {
+        return Err(StdError::generic_err(format!("listing is priced in {}, can not pay with {}", info.denom, paid.denom)));
+    }
+    if bidder==info.owner {
+        return Err(StdError::generic_err("the seller can not bid on their own auction"));
+    }
+    let (min_increment, end_time, bid)=match &mut info.kind {
+        SaleKind::English { min_increment, end_time, bid } => (*min_increment, *end_time, bid),
+        SaleKind::Sealed { end_time } => {
+            let end_time=*end_time;
+            return place_sealed_bid(deps, &state, env, tokenid, &info, end_time, bidder, paid);
+        }
+        _ => return Err(StdError::generic_err(format!("token {} is not up for auction", tokenid))),
+    };
+    if env.block.time>=end_time {
+        return Err(StdError::generic_err(format!("auction ended at {}", end_time)));
+    }
+    let min_bid=match bid {
+        Some(prev) => prev.amount.u128().checked_add(min_increment.u128()).map(Uint128)
+            .ok_or_else(||StdError::generic_err(format!("the minimum next bid exceeds the largest amount of {}", u128::MAX)))?,
+        None => info.price,
+    };
+    if paid.amount<min_bid {
+        return Err(StdError::generic_err(format!("bid must be at least {}{}, received {}{}", min_bid, info.denom, paid.amount, paid.denom)));
+    }
+
+    let mut res=vec![];
+    if let Some(prev)=bid.replace(Bid{ bidder, amount: paid.amount }) {
+        res.push(payment_msg(&state, &info.denom, prev.bidder, prev.amount)?);
+    }
+    store_set(&mut deps.storage,tokenid,&info)?;
+    Ok(res)
+}
+
+/// stores a sealed bid, replacing and refunding an earlier bid of the same bidder
+#[allow(clippy::too_many_arguments)]
+fn place_sealed_bid<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    state: &State,
+    env: &Env,
+    tokenid: &str,
+    info: &StoreNftInfo,
+    end_time: u64,
+    bidder: HumanAddr,
+    paid: Coin,
+) -> StdResult<Vec<CosmosMsg>> {
+    check_sealed_denom(state, info)?;
+    if env.block.time>=end_time {
+        return Err(StdError::generic_err(format!("auction ended at {}", end_time)));
+    }
+    if paid.amount<info.price {
+        return Err(StdError::generic_err(format!("bid must be at least {}{}, received {}{}", info.price, info.denom, paid.amount, paid.denom)));
+    }
+
+    let mut bids=sealed_bids_read(&deps.storage,tokenid)?;
+    let mut res=vec![];
+    if let Some(pos)=bids.iter().position(|b|b.bidder==bidder) {
+        let prev=bids.remove(pos);
+        res.push(payment_msg(state, &info.denom, prev.bidder, prev.amount)?);
+    }
+    bids.push(Bid{ bidder, amount: paid.amount });
+    sealed_bids_save(&mut deps.storage,tokenid,&bids)?;
+    Ok(res)
+}
+
+/// sealed auctions take SNIP-20 bids only, native funds sent with a bid and their refunds are public
+fn check_sealed_denom(state: &State, info: &StoreNftInfo) -> StdResult<()> {
+    if let SaleKind::Sealed { .. }=info.kind {
+        if !state.payment_tokens.iter().any(|t|t.address.as_str()==info.denom) {
+            return Err(StdError::generic_err("sealed auctions must be priced in a whitelisted SNIP-20 token, native bids are public"));
+        }
+    }
+    Ok(())
+}
+
+/// sells an ended auction to its highest bidder, refunding the other bids,
+/// or returns the token to the seller without bids
+pub fn finalize<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let mut info=store_read(&deps.storage,tokenid)?;
+    let (end_time, mut bids)=match &info.kind {
+        SaleKind::English { end_time, bid, .. } => (*end_time, bid.iter().cloned().collect::<Vec<Bid>>()),
+        SaleKind::Sealed { end_time } => (*end_time, sealed_bids_read(&deps.storage,tokenid)?),
+        _ => return Err(StdError::generic_err(format!("token {} is not up for auction", tokenid))),
+    };
+    if env.block.time<end_time {
+        return Err(StdError::generic_err(format!("auction ends at {}", end_time)));
+    }
+    // highest first, the earlier of equal bids wins
+    bids.sort_by_key(|bid|Reverse(bid.amount.u128()));
+    sealed_bids_save(&mut deps.storage,tokenid,&[])?;
+    let res=if bids.is_empty() {
+        let res=return_token(deps, &state, tokenid, &info)?;
+        store_remove(&mut deps.storage,tokenid)?;
+        res
+    } else {
+        let winner=bids.remove(0);
+        info.price=match info.kind {
+            // second price, the reserve if nobody else bid
+            SaleKind::Sealed { .. } => bids.first().map_or(info.price, |second|second.amount),
+            _ => winner.amount,
+        };
+        let denom=info.denom.clone();
+        let paid=Coin{ denom: denom.clone(), amount: winner.amount };
+        let mut res=sell(deps, &env, tokenid, info, winner.bidder.clone(), winner.bidder, paid)?;
+        for loser in bids {
+            res.push(payment_msg(&state, &denom, loser.bidder, loser.amount)?);
+        }
+        res
+    };
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "finalize"), plaintext_log("token_id", tokenid)],
+        data: None
+    })
+}
+
+pub fn make_offer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+    expires:Option<Expiration>,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let paid=match env.message.sent_funds.as_slice() {
+        [coin] if !coin.amount.is_zero() => coin.clone(),
+        _ => return Err(StdError::generic_err("an offer must send funds of a single denom")),
+    };
+    if state.payment_tokens.iter().any(|t|t.address.as_str()==paid.denom) {
+        return Err(StdError::generic_err(format!("can not offer {}", paid.denom)));
+    }
+    let expires=expires.unwrap_or(Expiration::Never);
+    if expires.is_expired(&env.block) {
+        return Err(StdError::generic_err("the offer is already expired"));
+    }
+    //makes sure the token exists, it does not need to be listed
+    get_ed_nft(deps, tokenid.clone(), &state)?;
+    if store_read(&deps.storage,tokenid).is_ok_and(|info|info.owner==env.message.sender) {
+        return Err(StdError::generic_err("the seller can not make an offer on their own listing"));
+    }
+
+    let offer=Offer{ buyer: env.message.sender.clone(), amount: paid.amount, denom: paid.denom, expires };
+    let res=match offer_set(&mut deps.storage,tokenid,offer)? {
+        Some(prev)=>vec![payment_msg(&state, &prev.denom, prev.buyer, prev.amount)?],
+        None=>vec![]
+    };
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "make_offer"), plaintext_log("token_id", tokenid)],
+        data: None
+    })
+}
+
+pub fn cancel_offer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let offer=offer_remove(&mut deps.storage,tokenid,&env.message.sender)?
+        .ok_or_else(||StdError::generic_err(format!("no offer on token {}", tokenid)))?;
+    Ok(HandleResponse{
+        messages: vec![payment_msg(&state, &offer.denom, offer.buyer, offer.amount)?],
+        log: vec![plaintext_log("action", "cancel_offer"), plaintext_log("token_id", tokenid)],
+        data: None
+    })
+}
+
+pub fn accept_listed_offer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+    buyer:HumanAddr,
+) -> StdResult<HandleResponse> {
+    let info=store_read(&deps.storage,tokenid)?;
+    if env.message.sender!=info.owner {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    check_unbundled(&deps.storage, tokenid)?;
+    if has_bids(&deps.storage, tokenid, &info)? {
+        return Err(StdError::generic_err("the auction has a bid, finalize it instead"));
+    }
+    accept_offer(deps, env, tokenid, info, buyer)
+}
+
+/// sells the escrowed token to `buyer` for their offer, `info` being its listing
+/// or the seller and payout of a token sent in to accept the offer
+fn accept_offer<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid: &String,
+    mut info: StoreNftInfo,
+    buyer: HumanAddr,
+) -> StdResult<HandleResponse> {
+    let offer=offers_read(&deps.storage,tokenid)?.into_iter().find(|o|o.buyer==buyer)
+        .ok_or_else(||StdError::generic_err(format!("no offer from {} on token {}", buyer, tokenid)))?;
+    if offer.expires.is_expired(&env.block) {
+        return Err(StdError::generic_err("the offer is expired"));
+    }
+    offer_remove(&mut deps.storage,tokenid,&buyer)?;
+    info.price=offer.amount;
+    info.denom=offer.denom.clone();
+    let paid=Coin{ denom: offer.denom, amount: offer.amount };
+    let res=sell(deps, &env, tokenid, info, offer.buyer.clone(), offer.buyer, paid)?;
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "accept_offer"), plaintext_log("token_id", tokenid)],
+        data: None
+    })
+}
+
+/// moves the listed token to `recipient` and pays the seller out of `paid`,
+/// refunding anything above the listed price to `buyer`
+fn sell<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    tokenid: &String,
+    info: StoreNftInfo,
+    buyer: HumanAddr,
+    recipient: HumanAddr,
+    paid: Coin,
+) -> StdResult<Vec<CosmosMsg>> {
+    let state=config_read(&deps.storage).load()?;
+    if paid.denom!=info.denom {
+        return Err(StdError::generic_err(format!("listing is priced in {}, can not pay with {}", info.denom, paid.denom)));
+    }
+    if paid.amount<info.price {
+        return Err(StdError::generic_err(format!("listing price is {}{}, received {}{}", info.price, info.denom, paid.amount, paid.denom)));
+    }
+    let excess=(paid.amount-info.price)?;
+
+    let mut res=vec![transfer_nft_msg(recipient, tokenid.clone(), None, None, 256,
+                                      state.ed_code_hash.to_owned(),
+                                      deps.api.human_address(&state.ed_nft_contract)?)?
+    ];
+    for (to, amount) in sale_payouts(deps, &state, tokenid, info.price, &info.denom, info.payout_addr())? {
+        res.push(payment_msg(&state, &info.denom, to, amount)?);
+    }
+    if !excess.is_zero() {
+        res.push(payment_msg(&state, &info.denom, buyer.clone(), excess)?);
+    }
+    record_sale(&mut deps.storage, &SaleRecord{
+        token_id: tokenid.clone(),
+        seller: info.owner,
+        buyer,
+        price: info.price,
+        denom: info.denom,
+        block_height: env.block.height,
+        block_time: env.block.time,
+    })?;
+    store_remove(&mut deps.storage,tokenid)?;
+    Ok(res)
+}
+
+/// splits the `price` a token sold for into the platform fee, kept by this contract until withdrawn,
+/// its royalties and the remainder for `seller`, skipping empty payouts
+fn sale_payouts<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    state: &State,
+    tokenid: &str,
+    price: Uint128,
+    denom: &str,
+    seller: HumanAddr,
+) -> StdResult<Vec<(HumanAddr, Uint128)>> {
+    let mut payouts=match query_royalties(deps, state, tokenid)? {
+        Some(royalty)=>royalty_payouts(&royalty, price)?,
+        None=>vec![]
+    };
+    let fee=mul_ratio(price, state.fee_bps as u128, 10000)?;
+    if !fee.is_zero() {
+        fees_add(&mut deps.storage, denom, fee)?;
+    }
+    let royalties=payouts.iter().map(|(_, amount)|amount.u128()).sum::<u128>();
+    let proceeds=price.u128().checked_sub(fee.u128()+royalties)
+        .ok_or_else(||StdError::generic_err("royalties and platform fee exceed the price"))?;
+    payouts.insert(0, (seller, Uint128(proceeds)));
+    payouts.retain(|(_, amount)|!amount.is_zero());
+    Ok(payouts)
+}
+
+pub fn set_fee<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    fee_bps: u16,
+    treasury: Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let api=&deps.api.clone();
+    config(&mut deps.storage).update(|mut state| {
+        if env.message.sender!=api.human_address(&state.owner)? { Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) }) }
+        else {
+            state.fee_bps=check_fee(fee_bps)?;
+            if let Some(treasury)=&treasury { state.treasury=api.canonical_address(treasury)?; }
+            Ok(state) }
+    })?;
+
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![plaintext_log("action", "set_fee"), plaintext_log("fee_bps", fee_bps)],
+        data: None
+    })
+}
+
+pub fn withdraw_fees<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    if env.message.sender!=deps.api.human_address(&state.owner)? {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    let treasury=deps.api.human_address(&state.treasury)?;
+    let res=fees_read(&deps.storage)?.into_iter()
+        .map(|fee|payment_msg(&state, &fee.denom, treasury.clone(), fee.amount))
+        .collect::<StdResult<Vec<CosmosMsg>>>()?;
+    fees_clear(&mut deps.storage);
+
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![],
+        data: None
+    })
+}
+
+pub fn set_gating_rule<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    rule: GatingRule,
+) -> StdResult<HandleResponse> {
+    let api=&deps.api.clone();
+    config(&mut deps.storage).update(|mut state| {
+        if env.message.sender!=api.human_address(&state.owner)? { Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) }) }
+        else {
+            state.gating_rule=rule;
+            Ok(state) }
+    })?;
+
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![plaintext_log("action", "set_gating_rule")],
+        data: None
+    })
+}
+
+pub fn set_scan_limit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    limit: u32,
+) -> StdResult<HandleResponse> {
+    if limit==0 {
+        return Err(StdError::generic_err("the scan limit must be positive"));
+    }
+    let api=&deps.api.clone();
+    config(&mut deps.storage).update(|mut state| {
+        if env.message.sender!=api.human_address(&state.owner)? { Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) }) }
+        else {
+            state.ip_scan_limit=limit;
+            Ok(state) }
+    })?;
+
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![plaintext_log("action", "set_scan_limit"), plaintext_log("limit", limit)],
+        data: None
+    })
+}
+
+pub fn claim_access<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let ednft=get_ed_nft(deps, tokenid.clone(), &state)?;
+    let viewer=env.message.sender;
+    let mut log=vec![plaintext_log("action", "claim_access"), plaintext_log("token_id", tokenid)];
+    match ip_access(deps, &state, viewer.clone(), &ednft) {
+        Ok(true) => {
+            let expires=env.block.time+ACCESS_GRANT_SECS;
+            grant_save(&mut deps.storage, &viewer, tokenid, &AccessGrant{ rule: state.gating_rule, expires })?;
+            log.push(plaintext_log("expires", expires));
+        }
+        //failing would keep the earlier grant, so the denial is only logged
+        denied => {
+            grant_remove(&mut deps.storage, &viewer, tokenid);
+            let reason=denied.err().map_or_else(||"no matching IP NFT".to_string(), |e|e.to_string());
+            log.push(plaintext_log("denied", reason));
+        }
+    }
+
+    Ok(HandleResponse{
+        messages: vec![],
+        log,
+        data: None
+    })
+}
+
+fn check_fee(fee_bps: u16) -> StdResult<u16> {
+    if fee_bps>10000 {
+        return Err(StdError::generic_err("fee_bps can not exceed 10000"));
+    }
+    Ok(fee_bps)
+}
+
+/// royalties the ED contract reports for `tokenid`. A failed query fails the sale, so royalties
+/// are never skipped without a trace
+fn query_royalties<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,state:&State,tokenid:&str) -> StdResult<Option<DisplayRoyaltyInfo>> {
+    let ed_viewer =Some(ViewerInfo{ address: state.contract_addr.to_owned(),
+        viewing_key: state.viewing_key.clone().add(SUFFIX_ED_KEY) });
+    let ed_contr_addr=deps.api.human_address(&state.ed_nft_contract)?;
+    let res: RoyaltyInfoResponse=Snip721QueryMsg::RoyaltyInfo { token_id: Some(tokenid.to_string()), viewer: ed_viewer }
+        .query(&deps.querier, state.ed_code_hash.to_owned(), ed_contr_addr)?;
+    Ok(res.royalty_info.royalty_info)
+}
+
+/// royalty amounts of a sale for `price`, each rounded down so rounding dust stays with the seller.
+/// Errors if a recipient is hidden from this contract, rather than paying its share to the seller
+fn royalty_payouts(royalty: &DisplayRoyaltyInfo, price: Uint128) -> StdResult<Vec<(HumanAddr, Uint128)>> {
+    let denominator=10u128.checked_pow(royalty.decimal_places_in_rates as u32)
+        .ok_or_else(||StdError::generic_err("invalid royalty decimal places"))?;
+    let rates: u128=royalty.royalties.iter().map(|r|r.rate as u128).sum();
+    if rates>denominator {
+        return Err(StdError::generic_err("royalty rates exceed 100%"));
+    }
+    royalty.royalties.iter()
+        .map(|r|{
+            let to=r.recipient.clone().ok_or_else(||StdError::generic_err("royalty recipient is hidden from the market"))?;
+            Ok((to, mul_ratio(price, r.rate as u128, denominator)?))
+        })
+        .collect()
+}
+
+pub fn add_payment_token<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: Snip20Token,
 ) -> StdResult<HandleResponse> {
     let api=&deps.api.clone();
     let mut res_msg:Vec<CosmosMsg>=vec![];
     config(&mut deps.storage).update(|mut state| {
         if env.message.sender!=api.human_address(&state.owner)? { Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) }) }
         else {
-            res_msg=vec![set_viewing_key_msg(vkey.clone().add(SUFFIX_IP_KEY), None, 256,
-                                state.ip_code_hash.to_owned(), api.human_address(&state.ip_nft_contract)?)?,
-            set_viewing_key_msg(vkey.clone().add(SUFFIX_ED_KEY), None, 256,
-                                state.ed_code_hash.to_owned(), api.human_address(&state.ed_nft_contract)?)?];
-            state.viewing_key=vkey;
+            if !state.payment_tokens.contains(&token) {
+                res_msg.push(register_receive_msg(env.contract_code_hash.clone(), None, 256,
+                                                  token.code_hash.to_owned(), token.address.to_owned())?);
+                state.payment_tokens.push(token);
+            }
             Ok(state) }
     })?;
 
@@ -110,36 +988,111 @@ pub fn set_up<S: Storage, A: Api, Q: Querier>(
     })
 }
 
-pub fn buy<S: Storage, A: Api, Q: Querier>(
+pub fn cancel_listing<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     tokenid:&String,
-    receipient:Option<HumanAddr>
 ) -> StdResult<HandleResponse> {
     let state=config_read(&deps.storage).load()?;
-    let sender=&env.message.sender;
-    let fee= check_fund(&env.message.sent_funds);
-    if *sender!=deps.api.human_address(&state.owner)?&&!fee.is_some() {
-        return Err(StdError::GenericErr { msg: "".to_string(), backtrace: None });
+    let info=store_read(&deps.storage,tokenid)?;
+    if env.message.sender!=info.owner {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    check_unbundled(&deps.storage, tokenid)?;
+    if has_bids(&deps.storage, tokenid, &info)? {
+        return Err(StdError::generic_err("the auction has a bid and can not be cancelled"));
     }
 
-    let mut res=vec![transfer_nft_msg(receipient.unwrap_or_else(||sender.to_owned()),
-                                      tokenid.clone(), None, None, 256,
-                                      state.ed_code_hash,
-                                      deps.api.human_address(&state.ed_nft_contract)?)?
-    ];
-    if fee.is_some() {
-        let info = store_read(&deps.storage,tokenid).unwrap();
-        res.push(CosmosMsg::Bank(BankMsg::Send {
-            from_address: env.contract.address,
-            to_address: info.owner,
-            amount: env.message.sent_funds
-        }));
+    let res=return_token(deps, &state, tokenid, &info)?;
+    store_remove(&mut deps.storage,tokenid)?;
+    Ok(HandleResponse{
+        messages: res,
+        log: vec![plaintext_log("action", "cancel_listing"), plaintext_log("token_id", tokenid)],
+        data: None
+    })
+}
+
+pub fn reclaim_expired<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+) -> StdResult<HandleResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let info=store_read(&deps.storage,tokenid)?;
+    if !info.is_expired(&env.block) {
+        return Err(StdError::generic_err(format!("the listing of token {} has not expired", tokenid)));
+    }
+
+    let res=return_token(deps, &state, tokenid, &info)?;
+    let mut log=vec![plaintext_log("action", "reclaim_expired"), plaintext_log("token_id", tokenid)];
+    //the bundle can not be sold without this token, its other tokens stay listed on their own
+    if let Some(bundle_id)=bundle_of(&deps.storage, tokenid)? {
+        bundle_remove(&mut deps.storage,bundle_id)?;
+        log.push(plaintext_log("bundle_id", bundle_id));
     }
-    store_remove(&mut deps.storage,tokenid);
+    store_remove(&mut deps.storage,tokenid)?;
     Ok(HandleResponse{
         messages: res,
-        log: vec![],
+        log,
+        data: None
+    })
+}
+
+/// whether bid funds are escrowed for the listing `info` of `tokenid`
+fn has_bids<S: Storage>(storage: &S, tokenid: &str, info: &StoreNftInfo) -> StdResult<bool> {
+    Ok(info.bid().is_some()||!sealed_bids_read(storage,tokenid)?.is_empty())
+}
+
+/// messages handing an escrowed token back to its seller
+fn return_token<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    state: &State,
+    tokenid: &str,
+    info: &StoreNftInfo,
+) -> StdResult<Vec<CosmosMsg>> {
+    let ed_contr_addr=deps.api.human_address(&state.ed_nft_contract)?;
+    //revoke before the transfer, this contract can not change approvals once it no longer holds the token
+    Ok(vec![
+        set_whitelisted_approval_msg(info.owner.clone(), Some(tokenid.to_string()),
+                                     Some(AccessLevel::RevokeToken), Some(AccessLevel::RevokeToken),
+                                     None, None, None, 256,
+                                     state.ed_code_hash.to_owned(), ed_contr_addr.clone())?,
+        transfer_nft_msg(info.owner.clone(), tokenid.to_string(), None, None, 256,
+                         state.ed_code_hash.to_owned(), ed_contr_addr)?,
+    ])
+}
+
+pub fn update_listing<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    tokenid:&String,
+    price:Coin,
+    payout:Option<HumanAddr>,
+) -> StdResult<HandleResponse> {
+    let mut info=store_read(&deps.storage,tokenid)?;
+    if env.message.sender!=info.owner {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    if has_bids(&deps.storage, tokenid, &info)? {
+        return Err(StdError::generic_err("the auction has a bid and can not be updated"));
+    }
+    if let SaleKind::Dutch { .. }=info.kind {
+        return Err(StdError::generic_err("Dutch auctions can not be updated, cancel and list again"));
+    }
+    info.price=validate_price(&price)?;
+    info.denom=price.denom;
+    if payout.is_some() { info.payout=payout; }
+    check_sealed_denom(&config_read(&deps.storage).load()?, &info)?;
+    store_set(&mut deps.storage,tokenid,&info)?;
+
+    Ok(HandleResponse{
+        messages: vec![],
+        log: vec![
+            plaintext_log("action", "update_listing"),
+            plaintext_log("token_id", tokenid),
+            plaintext_log("price", info.price),
+            plaintext_log("denom", &info.denom),
+        ],
         data: None
     })
 }
@@ -149,52 +1102,106 @@ pub fn query<S: Storage, A: Api, Q: Querier>(
     msg: QueryMsg,
 ) -> StdResult<Binary> {
     match msg {
-        QueryMsg::ViewNft {token_id,permit}=>to_binary(&check_view_nft(deps,&token_id,permit)?),
+        QueryMsg::ViewNft {token_id,permit,time}=>to_binary(&check_view_nft(deps,&token_id,permit,time)?),
         QueryMsg::GetConfig {permit} => to_binary(&query_config(deps,permit)?),
+        QueryMsg::Listings {start_after,limit,time} => to_binary(&query_listings(deps,start_after,limit,time)?),
+        QueryMsg::MyListings {permit,page,page_size} => to_binary(&query_my_listings(deps,permit,page,page_size)?),
+        QueryMsg::SaleHistory {permit,filter,page,page_size} => to_binary(&query_sale_history(deps,permit,filter,page,page_size)?),
+        QueryMsg::OffersMade {permit,page,page_size} => to_binary(&query_offers(deps,permit,false,page,page_size)?),
+        QueryMsg::OffersReceived {permit,page,page_size} => to_binary(&query_offers(deps,permit,true,page,page_size)?),
+        QueryMsg::Bundle {bundle_id} => to_binary(&bundle_read(&deps.storage,bundle_id)?),
+        QueryMsg::MyBid {permit,token_id} => to_binary(&query_my_bid(deps,permit,&token_id)?),
     }
 }
 
-fn check_view_nft<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,tokenid:&String,permit:Option<Permit>)->StdResult<NftResponse>{
+/// the ED dossier of a listed token. Its private metadata is only shown to permit signers whose IP NFTs
+/// satisfy the gating rule, other signers are refused and callers without a permit get the public data
+fn check_view_nft<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,tokenid:&String,permit:Option<Permit>,time:Option<u64>)->StdResult<NftResponse>{
     let state=&config_read(&deps.storage).load()?;
-    let ednft=&get_ed_nft(&deps, tokenid.clone(), state)?;
+    let mut ednft=get_ed_nft(deps, tokenid.clone(), state)?;
     let storeinfo=store_read(&deps.storage,tokenid)?;
-    if permit.is_some() {
-        let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit.unwrap(), state.contract_addr.to_owned(), None)?);
-        let ip_viewer =Some(ViewerInfo{ address: state.contract_addr.to_owned(),
-            viewing_key: state.viewing_key.clone().add(SUFFIX_IP_KEY) });
-        let ipnfts=tokens_query(&deps.querier, sender, Some(state.contract_addr.clone()),
-                               Some(ip_viewer.to_owned().unwrap().viewing_key),
-                                None, Option::Some(100),256,
-                               state.ip_code_hash.to_owned(),
-                               deps.api.human_address(&state.ip_nft_contract)?)?;
-
-        let ed_traits = find_trait(ednft.to_owned().public_metadata).unwrap_or_else(||vec![].into_iter()).find(
-                                 |tr| tr.trait_type.is_some()&&"agc"==tr.trait_type.as_ref().unwrap());
-
-        //todo:uncomment unwrap().. below,
-        // currently will panic since ipNft haven't standard "agc" trait, later should have
-        let ed_agc =&String::from("test");//&ed_traits.unwrap().value;
-
-        let ip_contr_addr =&deps.api.human_address(&state.ip_nft_contract)?;
-
-        let view=ipnfts.tokens.iter().find(|&ipnft|{
-            let detail=nft_dossier_query(&deps.querier, String::from(ipnft), ip_viewer.to_owned(),
-                                         Option::Some(true), 256,
-                                         state.ip_code_hash.to_owned(),
-                                         ip_contr_addr.to_owned());
-
-            if detail.is_err(){false}
-            else{
-                let data=detail.unwrap().public_metadata;
-                find_trait(data).unwrap_or_else(||vec![].into_iter()).find(
-                    |t| t.trait_type.is_some()&&t.trait_type.as_ref().unwrap()==ed_agc).is_some()
+    //the caller's time can only move the clock forward, an old time does not revive a grant
+    let now=block_time_read(&deps.storage)?.max(time.unwrap_or(0));
+    match permit {
+        Some(permit) => {
+            let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr.to_owned(), None)?);
+            let granted=grant_read(&deps.storage, &sender, tokenid)?.is_some_and(|grant|grant.is_valid(&state.gating_rule, now));
+            if !granted && !ip_access(deps, state, sender, &ednft)? {
+                return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
             }
-        }).is_some();
+        }
+        None => {
+            ednft.private_metadata=None;
+            ednft.display_private_metadata_error=Some("private metadata requires a matching IP NFT".to_string());
+            ednft.token_approvals=None;
+            ednft.inventory_approvals=None;
+        }
     }
 
-    //todo:verify by view
-    // let r=ednft.to_owned();
-    Ok(NftResponse{ dossier: ednft.clone(), store_info: storeinfo})
+    let current_price=storeinfo.price_at(now)?;
+    Ok(NftResponse{ dossier: ednft, store_info: storeinfo, current_price })
+}
+
+/// whether the IP NFTs of `holder` satisfy the gating rule for `ednft`. They are scanned page by page
+/// until the rule is satisfied, failing once more than `ip_scan_limit` tokens would be examined
+fn ip_access<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,state:&State,holder:HumanAddr,ednft:&NftDossier) -> StdResult<bool> {
+    let ip_viewer=ViewerInfo{ address: state.contract_addr.to_owned(),
+        viewing_key: state.viewing_key.clone().add(SUFFIX_IP_KEY) };
+    let ip_contr_addr=deps.api.human_address(&state.ip_nft_contract)?;
+    let ip_tokens=|start_after: Option<String>, limit: u32| tokens_query(&deps.querier, holder.clone(), Some(ip_viewer.address.clone()),
+                                                                        Some(ip_viewer.viewing_key.clone()), start_after, Some(limit), 256,
+                                                                        state.ip_code_hash.to_owned(), ip_contr_addr.clone())
+        .map(|list|list.tokens);
+
+    let rule=&state.gating_rule;
+    let mut evaluator=Evaluator::new(rule, ednft.public_metadata.as_ref());
+    if evaluator.is_satisfied() {
+        return Ok(true);
+    }
+    let mut scanned=0u32;
+    let mut start_after=None;
+    loop {
+        if scanned>=state.ip_scan_limit {
+            if ip_tokens(start_after, 1)?.is_empty() {
+                return Ok(false);
+            }
+            return Err(StdError::generic_err(format!("the viewer holds more than {} IP tokens, the scan limit of the gating rule", state.ip_scan_limit)));
+        }
+        let limit=IP_PAGE_SIZE.min(state.ip_scan_limit-scanned);
+        let page=ip_tokens(start_after, limit)?;
+        let mut metadata=if rule.needs_metadata() {
+            ip_metadata(&deps.querier, state, &ip_viewer, &ip_contr_addr, &page)
+        } else { HashMap::new() };
+        for token_id in &page {
+            scanned+=1;
+            if evaluator.add(&IpToken{ token_id: token_id.clone(), metadata: metadata.remove(token_id) }) {
+                return Ok(true);
+            }
+        }
+        if (page.len() as u32)<limit {
+            return Ok(false);
+        }
+        start_after=page.last().cloned();
+    }
+}
+
+/// public metadata of the IP tokens `token_ids` by one BatchNftDossier query, falling back to a query per
+/// token for IP contracts without batch support. Tokens this contract can not read are left out
+fn ip_metadata<Q: Querier>(querier: &Q,state:&State,ip_viewer:&ViewerInfo,ip_contr_addr:&HumanAddr,token_ids:&[String]) -> HashMap<String, Metadata> {
+    let batch: StdResult<BatchNftDossierResponse>=Snip721QueryMsg::BatchNftDossier { token_ids: token_ids.to_vec(), viewer: Some(ip_viewer.clone()), include_expired: Some(true) }
+        .query(querier, state.ip_code_hash.to_owned(), ip_contr_addr.clone());
+    if let Ok(batch)=batch {
+        return batch.batch_nft_dossier.nft_dossiers.into_iter()
+            .filter_map(|dossier|Some((dossier.token_id, dossier.public_metadata?)))
+            .collect();
+    }
+    token_ids.iter()
+        .filter_map(|token_id|{
+            let dossier=nft_dossier_query(querier, token_id.clone(), Some(ip_viewer.clone()), Some(true), 256,
+                                          state.ip_code_hash.to_owned(), ip_contr_addr.clone()).ok()?;
+            Some((token_id.clone(), dossier.public_metadata?))
+        })
+        .collect()
 }
 
 fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Option<Permit>) -> StdResult<ConfigResponse> {
@@ -205,21 +1212,134 @@ fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Op
         ip_nft_contract: deps.api.human_address(&state.ip_nft_contract)?,
         ip_code_hash: state.ip_code_hash,
         owner: deps.api.human_address(&state.owner)?,
-        view_key: None
+        view_key: None,
+        fee_bps: state.fee_bps,
+        treasury: deps.api.human_address(&state.treasury)?,
+        accrued_fees: fees_read(&deps.storage)?,
+        gating_rule: state.gating_rule,
+        ip_scan_limit: state.ip_scan_limit,
     };
-    if permit.is_some() {
-        let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit.unwrap(), state.contract_addr.to_owned(), None)?);
+    if let Some(permit) = permit {
+        let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr.to_owned(), None)?);
         if sender==r.owner { r.view_key= Some(state.viewing_key); }
     }
     Ok(r)
 }
 
-fn find_trait(metadata:Option<Metadata>) ->Option<IntoIter<Trait>>{
-    Some(metadata?.extension?.attributes?.into_iter())
+fn query_listings<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,start_after:Option<String>,limit:Option<u32>,time:Option<u64>) -> StdResult<ListingsResponse> {
+    let limit=limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let ids=listed_tokens(&deps.storage, start_after.as_deref(), limit)?;
+    let now=block_time_read(&deps.storage)?.max(time.unwrap_or(0));
+    Ok(ListingsResponse{ listings: read_listings(deps, ids.iter(), now)? })
+}
+
+fn query_my_listings<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Permit,page:Option<u32>,page_size:Option<u32>) -> StdResult<ListingsResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let seller=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr, None)?);
+    let page_size=page_size.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let ids=seller_tokens(&deps.storage,&seller)?;
+    let skip=(page.unwrap_or(0) as usize).saturating_mul(page_size);
+    Ok(ListingsResponse{ listings: read_listings(deps, ids.iter().skip(skip).take(page_size), block_time_read(&deps.storage)?)? })
+}
+
+fn query_sale_history<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Permit,filter:SaleFilter,page:Option<u32>,page_size:Option<u32>) -> StdResult<SaleHistoryResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr, None)?);
+    if filter==SaleFilter::All&&sender!=deps.api.human_address(&state.owner)? {
+        return Err(StdError::Unauthorized { backtrace: Some(Backtrace::generate()) });
+    }
+    let (sales,total)=sales_read(&deps.storage, &filter, &sender, page.unwrap_or(0),
+                                 page_size.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT))?;
+    Ok(SaleHistoryResponse{ sales, total })
+}
+
+/// offers made by the permit signer, or made on the tokens they listed if `received`
+fn query_offers<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Permit,received:bool,page:Option<u32>,page_size:Option<u32>) -> StdResult<OffersResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let sender=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr, None)?);
+    let page_size=page_size.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let ids=if received { seller_tokens(&deps.storage,&sender)? } else { offered_tokens(&deps.storage,&sender)? };
+    let mut offers=vec![];
+    for id in ids {
+        for offer in offers_read(&deps.storage,&id)? {
+            if received||offer.buyer==sender {
+                offers.push(TokenOffer{ token_id: id.clone(), offer });
+            }
+        }
+    }
+    let skip=(page.unwrap_or(0) as usize).saturating_mul(page_size);
+    Ok(OffersResponse{ offers: offers.into_iter().skip(skip).take(page_size).collect() })
+}
+
+fn query_my_bid<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,permit:Permit,tokenid:&str) -> StdResult<BidResponse> {
+    let state=config_read(&deps.storage).load()?;
+    let bidder=HumanAddr(validate(deps, PREFIX_PERMITS, &permit, state.contract_addr, None)?);
+    let amount=sealed_bids_read(&deps.storage,tokenid)?.into_iter()
+        .find(|bid|bid.bidder==bidder)
+        .map(|bid|bid.amount);
+    Ok(BidResponse{ amount })
+}
+
+/// listings of `ids`, priced at block `time`
+fn read_listings<'a, S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,ids:impl Iterator<Item=&'a String>,time:u64) -> StdResult<Vec<Listing>> {
+    ids.map(|id|{
+        let store_info=store_read(&deps.storage,id)?;
+        Ok(Listing{ token_id: id.clone(), current_price: store_info.price_at(time)?, store_info })
+    }).collect()
+}
+
+/// sums `fund`, which must all be in `denom`
+fn check_fund(fund: &[Coin], denom: &str) -> StdResult<Uint128> {
+    if let Some(foreign)=fund.iter().find(|c|c.denom!=denom) {
+        return Err(StdError::generic_err(format!("listing is priced in {}, can not pay with {}", denom, foreign.denom)));
+    }
+    Ok(Uint128(fund.iter().map(|c|c.amount.u128()).sum()))
+}
+
+/// funds sent for `info` with the message, rejecting listings priced in a SNIP-20 token
+fn native_payment(state: &State, env: &Env, info: &StoreNftInfo) -> StdResult<Coin> {
+    if state.payment_tokens.iter().any(|t|t.address.as_str()==info.denom) {
+        return Err(StdError::generic_err(format!("listing is priced in SNIP-20 {}, pay with a Send of that token", info.denom)));
+    }
+    Ok(Coin{ denom: info.denom.clone(), amount: check_fund(&env.message.sent_funds, &info.denom)? })
+}
+
+/// sets the price of `info` to what a buy in `block` pays, auctions and bundled tokens can not be bought
+fn buy_price<S: Storage>(storage: &S, tokenid: &str, info: &mut StoreNftInfo, block: &BlockInfo) -> StdResult<()> {
+    check_unbundled(storage, tokenid)?;
+    if info.is_expired(block) {
+        return Err(StdError::generic_err(format!("the listing of token {} expired", tokenid)));
+    }
+    let time=block.time;
+    match info.kind {
+        SaleKind::English { .. } | SaleKind::Sealed { .. } =>
+            Err(StdError::generic_err(format!("token {} is up for auction, place a Bid instead", tokenid))),
+        SaleKind::Dutch { start_time, .. } if time<start_time => Err(StdError::generic_err(format!("sale starts at {}", start_time))),
+        _ => {
+            info.price=info.price_at(time)?;
+            Ok(())
+        }
+    }
+}
+
+fn check_unbundled<S: Storage>(storage: &S, tokenid: &str) -> StdResult<()> {
+    match bundle_of(storage, tokenid)? {
+        Some(id) => Err(StdError::generic_err(format!("token {} is part of bundle {}, buy the bundle instead", tokenid, id))),
+        None => Ok(()),
+    }
 }
 
-fn check_fund(fund: &std::vec::Vec<Coin>) -> Option<&Coin> {
-    fund.iter().find(|c|c.denom=="uscrt"||c.amount>=Uint128(1000000))
+/// sends `amount` of `denom` held by this contract, `denom` being a native denom or a whitelisted SNIP-20 address
+fn payment_msg(state: &State, denom: &str, to: HumanAddr, amount: Uint128) -> StdResult<CosmosMsg> {
+    match state.payment_tokens.iter().find(|t|t.address.as_str()==denom) {
+        Some(token)=>transfer_msg(to, amount, None, None, 256,
+                                  token.code_hash.to_owned(), token.address.to_owned()),
+        None=>Ok(CosmosMsg::Bank(BankMsg::Send {
+            from_address: state.contract_addr.to_owned(),
+            to_address: to,
+            amount: vec![Coin { denom: denom.to_string(), amount }]
+        }))
+    }
 }
 
 fn get_ed_nft<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,tokenid:String,state:&State)->StdResult<NftDossier>{
@@ -234,32 +1354,182 @@ fn get_ed_nft<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>,tokenid:Str
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env};
-    use cosmwasm_std::{coins, from_binary};
+    use std::cell::Cell;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{coins, from_binary, from_slice, Empty, QuerierResult, QueryRequest, ReadonlyStorage, WasmQuery};
+    use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+    use secret_toolkit::snip721::{Extension, NftDossierResponse, TokenList, TokenListResponse, Trait};
+    use crate::msg::{DisplayRoyalty, RoyaltyInfo};
+    use crate::state::STORE_KEY;
     use schemars::_serde_json::{json, Value};
     use secret_toolkit::permit::{PermitParams, PermitSignature, PubKey, TokenPermissions};
     use secret_toolkit::serialization::{Json, Serde};
+    use secret_toolkit::snip721::Expiration;
+
+    static IP_C_ADDR: &str ="secret";
+    static IP_C_HASH: &str ="7be15101bd6dc6c991213f6b108c8626a1feb63312f8622cbe3e2243305a27bd";
+    static ED_ADDR: &str ="ed_contract";
+    static IP_ADDR: &str ="ip_contract";
+    static SNIP20_ADDR: &str ="snip20";
+
+    /// answers the SNIP-721 queries sent to the ED and IP contracts, everything else goes to MockQuerier
+    struct NftQuerier {
+        base: MockQuerier,
+        ed_dossiers: HashMap<String, NftDossier>,
+        ed_royalties: HashMap<String, DisplayRoyaltyInfo>,
+        /// error the ED contract answers RoyaltyInfo with
+        ed_royalty_error: Option<String>,
+        /// IP tokens by owner
+        ip_tokens: HashMap<String, Vec<String>>,
+        ip_dossiers: HashMap<String, NftDossier>,
+        /// whether the IP contract answers BatchNftDossier
+        ip_batch: bool,
+        /// dossier queries the IP contract answered, batched or not
+        ip_dossier_queries: Cell<u32>,
+    }
+
+    impl NftQuerier {
+        fn answer(&self, contract_addr: &HumanAddr, msg: &Binary) -> StdResult<Binary> {
+            let q: Value = schemars::_serde_json::from_slice(msg.as_slice()).unwrap();
+            if contract_addr.as_str()==ED_ADDR {
+                if let Some(dossier) = q.get("nft_dossier") {
+                    let token_id = dossier["token_id"].as_str().unwrap();
+                    let nft_dossier = self.ed_dossiers.get(token_id).cloned()
+                        .ok_or_else(|| StdError::generic_err(format!("token {} not found", token_id)))?;
+                    return to_binary(&NftDossierResponse { nft_dossier });
+                }
+                if let Some(royalty) = q.get("royalty_info") {
+                    if let Some(error) = &self.ed_royalty_error {
+                        return Err(StdError::generic_err(error));
+                    }
+                    let token_id = royalty["token_id"].as_str().unwrap();
+                    let royalty_info = self.ed_royalties.get(token_id).cloned();
+                    return to_binary(&RoyaltyInfoResponse { royalty_info: RoyaltyInfo { royalty_info } });
+                }
+            }
+            if contract_addr.as_str()==IP_ADDR {
+                if let Some(tokens) = q.get("tokens") {
+                    let owner = tokens["owner"].as_str().unwrap();
+                    let start_after = tokens["start_after"].as_str();
+                    let limit = tokens["limit"].as_u64().unwrap_or(30) as usize;
+                    let tokens = self.ip_tokens.get(owner).cloned().unwrap_or_default().into_iter()
+                        .skip_while(|id| start_after.is_some_and(|start| id.as_str() <= start))
+                        .take(limit)
+                        .collect();
+                    return to_binary(&TokenListResponse { token_list: TokenList { tokens } });
+                }
+                if let Some(batch) = q.get("batch_nft_dossier").filter(|_| self.ip_batch) {
+                    self.ip_dossier_queries.set(self.ip_dossier_queries.get() + 1);
+                    // answered like the reference contract, with fields the market does not read
+                    let nft_dossiers: Vec<Value> = batch["token_ids"].as_array().unwrap().iter()
+                        .filter_map(|id| {
+                            let mut element = schemars::_serde_json::to_value(self.ip_dossiers.get(id.as_str()?)?).unwrap();
+                            element["token_id"] = id.clone();
+                            element["transferable"] = json!(true);
+                            Some(element)
+                        })
+                        .collect();
+                    let batch = json!({ "batch_nft_dossier": { "nft_dossiers": nft_dossiers } });
+                    return Ok(Binary(schemars::_serde_json::to_vec(&batch).unwrap()));
+                }
+                if let Some(dossier) = q.get("nft_dossier") {
+                    self.ip_dossier_queries.set(self.ip_dossier_queries.get() + 1);
+                    let token_id = dossier["token_id"].as_str().unwrap();
+                    let nft_dossier = self.ip_dossiers.get(token_id).cloned()
+                        .ok_or_else(|| StdError::generic_err(format!("token {} not found", token_id)))?;
+                    return to_binary(&NftDossierResponse { nft_dossier });
+                }
+            }
+            Err(StdError::generic_err(format!("unsupported query {} to {}", q, contract_addr)))
+        }
+    }
 
-    static ipCAddr: &str ="secret";
-    static ipCHash: &str ="7be15101bd6dc6c991213f6b108c8626a1feb63312f8622cbe3e2243305a27bd";
+    impl Querier for NftQuerier {
+        fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+            let request: QueryRequest<Empty> = from_slice(bin_request).unwrap();
+            match request {
+                QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg, .. }) => Ok(self.answer(&contract_addr, &msg)),
+                _ => self.base.raw_query(bin_request),
+            }
+        }
+    }
+
+    fn dossier(owner: &str) -> NftDossier {
+        NftDossier {
+            owner: Some(HumanAddr::from(owner)),
+            public_metadata: None,
+            private_metadata: None,
+            display_private_metadata_error: None,
+            owner_is_public: false,
+            public_ownership_expiration: None,
+            private_metadata_is_public: false,
+            private_metadata_is_public_expiration: None,
+            token_approvals: None,
+            inventory_approvals: None,
+        }
+    }
+
+    fn market_deps() -> Extern<MockStorage, MockApi, NftQuerier> {
+        let mut deps = Extern {
+            storage: MockStorage::default(),
+            api: MockApi::new(20),
+            querier: NftQuerier {
+                base: MockQuerier::new(&[]),
+                ed_dossiers: HashMap::new(),
+                ed_royalties: HashMap::new(),
+                ed_royalty_error: None,
+                ip_tokens: HashMap::new(),
+                ip_dossiers: HashMap::new(),
+                ip_batch: true,
+                ip_dossier_queries: Cell::new(0),
+            },
+        };
+        let msg = InitMsg {
+            ed_ctr: HumanAddr::from(ED_ADDR),
+            ed_code_hash: String::from(IP_C_HASH),
+            ip_ctr: HumanAddr::from(IP_ADDR),
+            ip_code_hash: String::from(IP_C_HASH),
+            view_key: "key".to_string(),
+            payment_tokens: Some(vec![Snip20Token { address: HumanAddr::from(SNIP20_ADDR), code_hash: IP_C_HASH.to_string() }]),
+            fee_bps: None,
+            treasury: Some(HumanAddr::from("treasury")),
+            gating_rule: None
+        };
+        init(&mut deps, mock_env("creator", &[]), msg).unwrap();
+        deps
+    }
+
+    /// deposits `token_id` the way the ED contract does on SendNft
+    fn list(deps: &mut Extern<MockStorage, MockApi, NftQuerier>, token_id: &str, seller: &str, msg: &str) -> StdResult<HandleResponse> {
+        deps.querier.ed_dossiers.insert(token_id.to_string(), dossier(MOCK_CONTRACT_ADDR));
+        handle(deps, mock_env(ED_ADDR, &[]), HandleMsg::ReceiveNft {
+            sender: HumanAddr::from(seller),
+            token_id: token_id.to_string(),
+            msg: Some(Binary::from(msg.as_bytes())),
+        })
+    }
 
     #[test]
     fn proper_initialization() {
         let mut deps = mock_dependencies(20, &[]);
 
         let msg = InitMsg {
-            ed_ctr: HumanAddr(String::from(ipCAddr)),
-            ed_code_hash: String::from(ipCHash),
-            ip_ctr: HumanAddr(String::from(ipCAddr)),
-            ip_code_hash: String::from(ipCHash),
-            view_key: "".to_string()
+            ed_ctr: HumanAddr(String::from(IP_C_ADDR)),
+            ed_code_hash: String::from(IP_C_HASH),
+            ip_ctr: HumanAddr(String::from(IP_C_ADDR)),
+            ip_code_hash: String::from(IP_C_HASH),
+            view_key: "".to_string(),
+            payment_tokens: None,
+            fee_bps: None,
+            treasury: None,
+            gating_rule: None
         };
         let env = mock_env("creator", &coins(1000, "earth"));
 
         println!("{}",env.message.sender);
         // we can just call .unwrap() to assert this was a success
         let res = init(&mut deps, env.clone(), msg).unwrap();
-        assert_eq!(0, res.messages.len());
+        assert_eq!(3, res.messages.len());
 
         // it worked, let's query the state
         let res = query(&deps, QueryMsg::GetConfig { permit: None }).unwrap();
@@ -267,7 +1537,7 @@ mod tests {
         println!("{}", value.ip_code_hash);
         println!("{}", value.ip_nft_contract);
         assert_eq!(HumanAddr(String::from("creator")), value.owner);
-        assert_eq!(ipCHash, value.ed_code_hash);
+        assert_eq!(IP_C_HASH, value.ed_code_hash);
 
     }
 
@@ -283,7 +1553,7 @@ mod tests {
         println!("{}",v.unwrap_or(&Value::String("".parse().unwrap())));
 
         let t1="1000 secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k  other info";
-        let j3=StoreNftInfo::from(Binary::from(t1.as_bytes())).unwrap();
+        let j3=StoreNftInfo::from(Binary::from(t1.as_bytes()), HumanAddr::from("seller")).unwrap();
         println!("{}",j3.price);
         println!("{}",j3.owner);
 
@@ -294,24 +1564,761 @@ mod tests {
         // assert_eq!(r.unwrap_err(), StdError::serialize_err("secret_contract_example::state::StoreNftInfo ","u128 is not supported"));
     }
     #[test]
-    fn view() {
-        let mut deps = mock_dependencies(20, &coins(2, "token"));
+    fn listing_msg() {
+        let seller = HumanAddr::from("seller");
+        let msg = r#"{"list":{"price":{"amount":"1000","denom":"uscrt"},"payout":"secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k","expires_at":{"at_height":100}}}"#;
+        let info = StoreNftInfo::from_msg(Some(Binary::from(msg.as_bytes())), seller.clone()).unwrap();
+        assert_eq!(info, StoreNftInfo {
+            owner: seller.clone(),
+            price: Uint128(1000),
+            denom: "uscrt".to_string(),
+            payout: Some(HumanAddr::from("secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k")),
+            expires_at: Some(Expiration::AtHeight(100)),
+            kind: SaleKind::Fixed,
+        });
 
-        let msg = InitMsg {
-            ed_ctr: HumanAddr(String::from(ipCAddr)),
-            ed_code_hash: String::from(ipCHash),
-            ip_ctr: HumanAddr(String::from(ipCAddr)),
-            ip_code_hash: String::from(ipCHash),
-            view_key: "".to_string()
+        // legacy text is still accepted, paid out to the address in the text
+        let legacy = StoreNftInfo::from_msg(Some(Binary::from(b"1000 secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k".to_vec())), seller.clone()).unwrap();
+        assert_eq!(legacy.owner, seller);
+        assert_eq!(legacy.payout_addr(), HumanAddr::from("secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k"));
+
+        // malformed json is reported as such instead of falling back to the text format
+        let bad = StoreNftInfo::from_msg(Some(Binary::from(br#"{"list":{"price":1000}}"#.to_vec())), seller.clone());
+        match bad {
+            Err(StdError::ParseErr { .. }) => {}
+            _ => panic!("Must return parse error"),
+        }
+        assert!(StoreNftInfo::from_msg(None, seller.clone()).is_err());
+        assert!(StoreNftInfo::from_msg(Some(Binary::from(b"free".to_vec())), seller).is_err());
+    }
+
+    #[test]
+    fn legacy_price_entry() {
+        let mut deps = market_deps();
+        // listing as stored while prices were u64
+        PrefixedStorage::new(STORE_KEY, &mut deps.storage)
+            .set(b"1", br#"{"owner":"seller","price":1000}"#);
+        let info = store_read(&deps.storage, &"1".to_string()).unwrap();
+        assert_eq!((Uint128(1000), "uscrt"), (info.price, info.denom.as_str()));
+
+        store_set(&mut deps.storage, &"1".to_string(), &info).unwrap();
+        let stored = ReadonlyPrefixedStorage::new(STORE_KEY, &deps.storage).get(b"1").unwrap();
+        assert!(String::from_utf8(stored).unwrap().contains(r#""price":"1000""#));
+    }
+
+    #[test]
+    fn receive_nft() {
+        let mut deps = market_deps();
+        let msg = r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#;
+        let res = list(&mut deps, "1", "seller", msg).unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(HumanAddr::from("seller"), store_read(&deps.storage, &"1".to_string()).unwrap().owner);
+
+        // a callback from anyone but the ED contract is rejected
+        let spoofed = handle(&mut deps, mock_env("attacker", &[]), HandleMsg::ReceiveNft {
+            sender: HumanAddr::from("attacker"),
+            token_id: "1".to_string(),
+            msg: Some(Binary::from(r#"{"list":{"price":{"amount":"1","denom":"uscrt"}}}"#.as_bytes())),
+        });
+        match spoofed {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        let info = store_read(&deps.storage, &"1".to_string()).unwrap();
+        assert_eq!(HumanAddr::from("seller"), info.owner);
+        assert_eq!(Uint128(1000), info.price);
+
+        // the ED contract must have moved the token to this contract
+        deps.querier.ed_dossiers.insert("2".to_string(), dossier("seller"));
+        let res = handle(&mut deps, mock_env(ED_ADDR, &[]), HandleMsg::ReceiveNft {
+            sender: HumanAddr::from("seller"),
+            token_id: "2".to_string(),
+            msg: Some(Binary::from(msg.as_bytes())),
+        });
+        assert!(res.is_err());
+        assert!(store_read(&deps.storage, &"2".to_string()).is_err());
+    }
+
+    #[test]
+    fn batch_receive_nft() {
+        let mut deps = market_deps();
+        let batch = |deps: &mut Extern<MockStorage, MockApi, NftQuerier>, sender: &str, ids: &[&str], msg: &str| {
+            for id in ids {
+                deps.querier.ed_dossiers.insert(id.to_string(), dossier(MOCK_CONTRACT_ADDR));
+            }
+            handle(deps, mock_env(sender, &[]), HandleMsg::BatchReceiveNft {
+                sender: HumanAddr::from("operator"),
+                from: HumanAddr::from("creator"),
+                token_ids: ids.iter().map(|id| id.to_string()).collect(),
+                msg: Some(Binary::from(msg.as_bytes())),
+            })
+        };
+        let list_all = r#"{"list":{"price":{"amount":"50","denom":"uscrt"}}}"#;
+        match batch(&mut deps, "anyone", &["1"], list_all) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        let res = batch(&mut deps, ED_ADDR, &["1", "2", "3"], list_all).unwrap();
+        assert_eq!(3, res.messages.len());
+        assert_eq!(res.messages[2], set_whitelisted_approval_msg(HumanAddr::from("creator"), Some("3".to_string()),
+                                                                 Some(AccessLevel::ApproveToken), Some(AccessLevel::ApproveToken),
+                                                                 None, None, None, 256,
+                                                                 IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+        assert_eq!(vec!["1", "2", "3"], seller_tokens(&deps.storage, &HumanAddr::from("creator")).unwrap());
+
+        let list_each = r#"{"list_each":{"prices":[{"token_id":"4","price":{"amount":"10","denom":"uscrt"}},{"token_id":"5","price":{"amount":"20","denom":"uatom"}}]}}"#;
+        assert!(batch(&mut deps, ED_ADDR, &["4", "6"], list_each).is_err());
+        batch(&mut deps, ED_ADDR, &["4", "5"], list_each).unwrap();
+        let info = store_read(&deps.storage, &"5".to_string()).unwrap();
+        assert_eq!((Uint128(20), "uatom", HumanAddr::from("creator")), (info.price, info.denom.as_str(), info.owner));
+        // a per token price map needs a batch send
+        assert!(list(&mut deps, "7", "creator", list_each).is_err());
+
+        // single sends arrive as batches too and are decoded like ReceiveNft
+        batch(&mut deps, ED_ADDR, &["7", "8"], "300 payout").unwrap();
+        assert_eq!((Uint128(300), Some(HumanAddr::from("payout"))), store_read(&deps.storage, &"8".to_string()).map(|i| (i.price, i.payout)).unwrap());
+        let res = batch(&mut deps, ED_ADDR, &["9"], "500 payout").unwrap();
+        assert_eq!(res.log[0].value, "list");
+        assert_eq!(Uint128(500), store_read(&deps.storage, &"9".to_string()).unwrap().price);
+
+        deps.querier.ed_dossiers.insert("10".to_string(), dossier("holder"));
+        handle(&mut deps, mock_env("alice", &coins(300, "uscrt")), HandleMsg::MakeOffer { token_id: "10".to_string(), expires: None }).unwrap();
+        let accept = r#"{"accept_offer":{"buyer":"alice"}}"#;
+        assert!(batch(&mut deps, ED_ADDR, &["10", "11"], accept).is_err());
+        let res = batch(&mut deps, ED_ADDR, &["10"], accept).unwrap();
+        assert_eq!(res.messages[0], transfer_nft_msg(HumanAddr::from("alice"), "10".to_string(), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+        assert!(offers_read(&deps.storage, "10").unwrap().is_empty());
+    }
+
+    #[test]
+    fn buy_price() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"},"payout":"payout"}}"#).unwrap();
+        let transfer = HandleMsg::Transfer { token_id: "1".to_string(), receipient: None };
+
+        let res = handle(&mut deps, mock_env("buyer", &coins(999, "uscrt")), transfer.clone());
+        assert_eq!(res.unwrap_err(), StdError::generic_err("listing price is 1000uscrt, received 999uscrt"));
+        let res = handle(&mut deps, mock_env("buyer", &coins(1000, "uatom")), transfer.clone());
+        assert_eq!(res.unwrap_err(), StdError::generic_err("listing is priced in uscrt, can not pay with uatom"));
+        let mixed = vec![Coin::new(1000, "uscrt"), Coin::new(1, "uatom")];
+        assert!(handle(&mut deps, mock_env("buyer", &mixed), transfer.clone()).is_err());
+
+        let res = handle(&mut deps, mock_env("buyer", &coins(1500, "uscrt")), transfer).unwrap();
+        assert_eq!(3, res.messages.len());
+        assert_eq!(res.messages[1], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("payout"),
+            amount: coins(1000, "uscrt"),
+        }));
+        assert_eq!(res.messages[2], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("buyer"),
+            amount: coins(500, "uscrt"),
+        }));
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+
+        let (sales, total) = sales_read(&deps.storage, &SaleFilter::Sales, &HumanAddr::from("seller"), 0, 10).unwrap();
+        assert_eq!(1, total);
+        assert_eq!((HumanAddr::from("buyer"), Uint128(1000)), (sales[0].buyer.clone(), sales[0].price));
+        let (purchases, _) = sales_read(&deps.storage, &SaleFilter::Purchases, &HumanAddr::from("buyer"), 0, 10).unwrap();
+        assert_eq!(sales, purchases);
+        let (all, _) = sales_read(&deps.storage, &SaleFilter::All, &HumanAddr::from("anyone"), 0, 10).unwrap();
+        assert_eq!(sales, all);
+        assert_eq!(0, sales_read(&deps.storage, &SaleFilter::Purchases, &HumanAddr::from("seller"), 0, 10).unwrap().1);
+        assert!(sales_read(&deps.storage, &SaleFilter::Sales, &HumanAddr::from("seller"), u32::MAX, 30).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn owner_rescue() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let transfer = |token_id: &str| HandleMsg::Transfer { token_id: token_id.to_string(), receipient: Some(HumanAddr::from("owner")) };
+
+        // a listed token is not released without paying the seller
+        assert!(handle(&mut deps, mock_env("creator", &[]), transfer("1")).is_err());
+        assert!(store_read(&deps.storage, &"1".to_string()).is_ok());
+
+        let res = handle(&mut deps, mock_env("creator", &[]), transfer("2")).unwrap();
+        assert_eq!(res.messages, vec![transfer_nft_msg(HumanAddr::from("owner"), "2".to_string(), None, None, 256,
+                                                       IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap()]);
+        assert!(handle(&mut deps, mock_env("anyone", &[]), transfer("2")).is_err());
+    }
+
+    #[test]
+    fn batch_buy() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "alice", r#"{"list":{"price":{"amount":"100","denom":"uscrt"}}}"#).unwrap();
+        list(&mut deps, "2", "alice", r#"{"list":{"price":{"amount":"100","denom":"uscrt"}}}"#).unwrap();
+        list(&mut deps, "3", "bob", r#"{"list":{"price":{"amount":"50","denom":"uscrt"}}}"#).unwrap();
+        list(&mut deps, "4", "alice", r#"{"list":{"price":{"amount":"10","denom":"uatom"}}}"#).unwrap();
+        let token_ids = vec!["1".to_string(), "2".to_string(), "3".to_string(), "4".to_string()];
+        let buy = HandleMsg::BatchTransfer { token_ids: token_ids.clone(), recipient: None };
+
+        assert!(handle(&mut deps, mock_env("buyer", &coins(250, "uscrt")), buy.clone()).is_err());
+        let funds = vec![Coin::new(249, "uscrt"), Coin::new(10, "uatom")];
+        assert!(handle(&mut deps, mock_env("buyer", &funds), buy.clone()).is_err());
+        let funds = vec![Coin::new(200, "uscrt"), Coin::new(10, "uatom"), Coin::new(60, "uscrt")];
+        let res = handle(&mut deps, mock_env("buyer", &funds), buy).unwrap();
+        let send = |to: &str, amount: Vec<Coin>| CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from(to),
+            amount,
+        });
+        assert_eq!(res.messages, vec![
+            batch_transfer_nft_msg(vec![Transfer { recipient: HumanAddr::from("buyer"), token_ids, memo: None }],
+                                   None, 256, IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            send("alice", vec![Coin::new(200, "uscrt"), Coin::new(10, "uatom")]),
+            send("bob", coins(50, "uscrt")),
+            send("buyer", coins(10, "uscrt")),
+        ]);
+        assert!(listed_tokens(&deps.storage, None, 10).unwrap().is_empty());
+        assert_eq!(4, sales_read(&deps.storage, &SaleFilter::Purchases, &HumanAddr::from("buyer"), 0, 10).unwrap().1);
+    }
+
+    #[test]
+    fn bundles() {
+        let mut deps = market_deps();
+        for id in &["1", "2", "3"] {
+            list(&mut deps, id, "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        }
+        list(&mut deps, "4", "other", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        deps.querier.ed_royalties.insert("2".to_string(), DisplayRoyaltyInfo {
+            decimal_places_in_rates: 2,
+            royalties: vec![DisplayRoyalty { recipient: Some(HumanAddr::from("creator")), rate: 10 }],
+        });
+        let create = |ids: &[&str]| HandleMsg::CreateBundle {
+            token_ids: ids.iter().map(|id| id.to_string()).collect(),
+            price: Coin::new(301, "uscrt"),
+            payout: None,
+        };
+
+        assert!(handle(&mut deps, mock_env("seller", &[]), create(&["1"])).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &[]), create(&["1", "4"])).is_err());
+        let res = handle(&mut deps, mock_env("seller", &[]), create(&["1", "2"])).unwrap();
+        let id: u64 = from_binary(&res.data.unwrap()).unwrap();
+        assert!(handle(&mut deps, mock_env("seller", &[]), create(&["2", "3"])).is_err());
+
+        assert!(handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "2".to_string() }).is_err());
+        let buy = HandleMsg::BuyBundle { bundle_id: id, recipient: None };
+        assert!(handle(&mut deps, mock_env("buyer", &coins(300, "uscrt")), buy.clone()).is_err());
+        let res = handle(&mut deps, mock_env("buyer", &coins(310, "uscrt")), buy.clone()).unwrap();
+        let send = |to: &str, amount| CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from(to),
+            amount: coins(amount, "uscrt"),
+        });
+        assert_eq!(res.messages, vec![
+            batch_transfer_nft_msg(vec![Transfer { recipient: HumanAddr::from("buyer"), token_ids: vec!["1".to_string(), "2".to_string()], memo: None }],
+                                   None, 256, IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            send("seller", 286),
+            send("creator", 15),
+            send("buyer", 9),
+        ]);
+        assert!(handle(&mut deps, mock_env("buyer", &coins(310, "uscrt")), buy).is_err());
+        assert_eq!(vec!["3", "4"], listed_tokens(&deps.storage, None, 10).unwrap());
+
+        // sold tokens can not be bundled, cancelling a bundle lets its tokens be bought on their own again
+        assert!(handle(&mut deps, mock_env("seller", &[]), create(&["3", "1"])).is_err());
+        list(&mut deps, "5", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let res = handle(&mut deps, mock_env("seller", &[]), create(&["3", "5"])).unwrap();
+        let id: u64 = from_binary(&res.data.unwrap()).unwrap();
+        assert!(handle(&mut deps, mock_env("other", &[]), HandleMsg::CancelBundle { bundle_id: id }).is_err());
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelBundle { bundle_id: id }).unwrap();
+        handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "3".to_string(), receipient: None }).unwrap();
+    }
+
+    #[test]
+    fn buy_with_snip20() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"snip20"}}}"#).unwrap();
+        let buy = |amount: u128| HandleMsg::Receive {
+            sender: HumanAddr::from("buyer"),
+            from: HumanAddr::from("buyer"),
+            amount: Uint128(amount),
+            msg: Some(to_binary(&ReceiveMsg::Buy { token_id: "1".to_string(), recipient: None }).unwrap()),
+        };
+
+        // only whitelisted token contracts may call back
+        match handle(&mut deps, mock_env("other_token", &[]), buy(1000)) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        assert!(handle(&mut deps, mock_env("buyer", &coins(1000, "snip20")), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).is_err());
+
+        let res = handle(&mut deps, mock_env(SNIP20_ADDR, &[]), buy(1200)).unwrap();
+        assert_eq!(res.messages[1], transfer_msg(HumanAddr::from("seller"), Uint128(1000), None, None, 256,
+                                                 IP_C_HASH.to_string(), HumanAddr::from(SNIP20_ADDR)).unwrap());
+        assert_eq!(res.messages[2], transfer_msg(HumanAddr::from("buyer"), Uint128(200), None, None, 256,
+                                                 IP_C_HASH.to_string(), HumanAddr::from(SNIP20_ADDR)).unwrap());
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+    }
+
+    #[test]
+    fn buy_royalties() {
+        let mut deps = market_deps();
+        deps.querier.ed_royalties.insert("1".to_string(), DisplayRoyaltyInfo {
+            decimal_places_in_rates: 4,
+            royalties: vec![
+                DisplayRoyalty { recipient: Some(HumanAddr::from("creator")), rate: 500 },
+                DisplayRoyalty { recipient: Some(HumanAddr::from("artist")), rate: 250 },
+            ],
+        });
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1001","denom":"uscrt"}}}"#).unwrap();
+
+        let res = handle(&mut deps, mock_env("buyer", &coins(1001, "uscrt")), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).unwrap();
+        let paid: Vec<CosmosMsg> = vec![("seller", 926), ("creator", 50), ("artist", 25)].into_iter()
+            .map(|(to, amount)| CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from(to),
+                amount: coins(amount, "uscrt"),
+            }))
+            .collect();
+        assert_eq!(paid, res.messages[1..].to_vec());
+
+        // a hidden recipient fails the sale instead of paying its share to the seller
+        deps.querier.ed_royalties.insert("3".to_string(), DisplayRoyaltyInfo {
+            decimal_places_in_rates: 4,
+            royalties: vec![
+                DisplayRoyalty { recipient: Some(HumanAddr::from("creator")), rate: 500 },
+                DisplayRoyalty { recipient: None, rate: 100 },
+            ],
+        });
+        list(&mut deps, "3", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let res = handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "3".to_string(), receipient: None });
+        assert!(res.unwrap_err().to_string().contains("royalty recipient is hidden"));
+        assert!(store_read(&deps.storage, &"3".to_string()).is_ok());
+
+        // a royalty query that fails does not pay the seller the royalties
+        list(&mut deps, "2", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        deps.querier.ed_royalty_error = Some("invalid viewing key".to_string());
+        let res = handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "2".to_string(), receipient: None });
+        assert!(res.unwrap_err().to_string().contains("invalid viewing key"));
+    }
+
+    #[test]
+    fn platform_fee() {
+        let mut deps = market_deps();
+        let set_fee = HandleMsg::SetFee { fee_bps: 250, treasury: None };
+        match handle(&mut deps, mock_env("anyone", &[]), set_fee.clone()) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        handle(&mut deps, mock_env("creator", &[]), set_fee).unwrap();
+        deps.querier.ed_royalties.insert("1".to_string(), DisplayRoyaltyInfo {
+            decimal_places_in_rates: 2,
+            royalties: vec![DisplayRoyalty { recipient: Some(HumanAddr::from("creator")), rate: 10 }],
+        });
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        list(&mut deps, "2", "seller", r#"{"list":{"price":{"amount":"100","denom":"uscrt"}}}"#).unwrap();
+
+        let res = handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).unwrap();
+        assert_eq!(res.messages[1], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("seller"),
+            amount: coins(875, "uscrt"),
+        }));
+        handle(&mut deps, mock_env("buyer", &coins(100, "uscrt")), HandleMsg::Transfer { token_id: "2".to_string(), receipient: None }).unwrap();
+
+        let config: ConfigResponse = from_binary(&query(&deps, QueryMsg::GetConfig { permit: None }).unwrap()).unwrap();
+        assert_eq!((250, HumanAddr::from("treasury")), (config.fee_bps, config.treasury));
+        assert_eq!(coins(27, "uscrt"), config.accrued_fees);
+
+        assert!(handle(&mut deps, mock_env("anyone", &[]), HandleMsg::WithdrawFees {}).is_err());
+        let res = handle(&mut deps, mock_env("creator", &[]), HandleMsg::WithdrawFees {}).unwrap();
+        assert_eq!(res.messages, vec![CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("treasury"),
+            amount: coins(27, "uscrt"),
+        })]);
+        assert!(fees_read(&deps.storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn english_auction() {
+        let mut deps = market_deps();
+        let end = mock_env("anyone", &[]).block.time + 100;
+        let auction = format!(r#"{{"auction":{{"reserve":{{"amount":"100","denom":"uscrt"}},"min_increment":"10","end_time":{}}}}}"#, end);
+        list(&mut deps, "1", "seller", &auction).unwrap();
+        list(&mut deps, "2", "seller", &auction).unwrap();
+        let bid = HandleMsg::Bid { token_id: "1".to_string() };
+
+        assert!(handle(&mut deps, mock_env("alice", &coins(99, "uscrt")), bid.clone()).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &coins(100, "uscrt")), bid.clone()).is_err());
+        let res = handle(&mut deps, mock_env("alice", &coins(100, "uscrt")), bid.clone()).unwrap();
+        assert!(res.messages.is_empty());
+        assert!(handle(&mut deps, mock_env("bob", &coins(109, "uscrt")), bid.clone()).is_err());
+        let res = handle(&mut deps, mock_env("bob", &coins(110, "uscrt")), bid.clone()).unwrap();
+        assert_eq!(res.messages, vec![CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("alice"),
+            amount: coins(100, "uscrt"),
+        })]);
+
+        assert!(handle(&mut deps, mock_env("carol", &coins(500, "uscrt")), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "1".to_string() }).is_err());
+        let finalize = HandleMsg::Finalize { token_id: "1".to_string() };
+        assert!(handle(&mut deps, mock_env("anyone", &[]), finalize.clone()).is_err());
+
+        // a bid so high no higher one fits in an amount
+        list(&mut deps, "3", "seller", &auction).unwrap();
+        let bid_max = HandleMsg::Bid { token_id: "3".to_string() };
+        handle(&mut deps, mock_env("alice", &coins(u128::MAX, "uscrt")), bid_max.clone()).unwrap();
+        let res = handle(&mut deps, mock_env("bob", &coins(u128::MAX, "uscrt")), bid_max);
+        assert!(res.unwrap_err().to_string().contains(&u128::MAX.to_string()));
+
+        let mut env = mock_env("anyone", &[]);
+        env.block.time = end;
+        assert!(handle(&mut deps, env.clone(), bid).is_err());
+        let res = handle(&mut deps, env.clone(), finalize).unwrap();
+        assert_eq!(res.messages, vec![
+            transfer_nft_msg(HumanAddr::from("bob"), "1".to_string(), None, None, 256,
+                             IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("seller"),
+                amount: coins(110, "uscrt"),
+            }),
+        ]);
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+
+        // without bids the token goes back to the seller
+        let res = handle(&mut deps, env, HandleMsg::Finalize { token_id: "2".to_string() }).unwrap();
+        assert_eq!(res.messages[1], transfer_nft_msg(HumanAddr::from("seller"), "2".to_string(), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+    }
+
+    #[test]
+    fn dutch_auction() {
+        let mut deps = market_deps();
+        let start = mock_env("anyone", &[]).block.time;
+        let dutch = format!(r#"{{"dutch_auction":{{"start_price":{{"amount":"1000","denom":"uscrt"}},"end_price":"100","start_time":{},"end_time":{}}}}}"#, start + 10, start + 110);
+        let seller = validate(&deps, PREFIX_PERMITS, &view_permit(), HumanAddr::from(MOCK_CONTRACT_ADDR), None).unwrap();
+        list(&mut deps, "1", "seller", &dutch).unwrap();
+        list(&mut deps, "2", &seller, &dutch).unwrap();
+        let buy = HandleMsg::Transfer { token_id: "1".to_string(), receipient: None };
+        assert!(handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), buy.clone()).is_err());
+
+        let listings = |deps: &Extern<MockStorage, MockApi, NftQuerier>, time| {
+            let res = query(deps, QueryMsg::Listings { start_after: None, limit: None, time }).unwrap();
+            from_binary::<ListingsResponse>(&res).unwrap().listings.iter().map(|l| l.current_price.u128()).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![1000, 1000], listings(&deps, None));
+        assert_eq!(vec![730, 730], listings(&deps, Some(start + 40)));
+        assert_eq!(vec![100, 100], listings(&deps, Some(start + 500)));
+
+        let mut env = mock_env("buyer", &coins(800, "uscrt"));
+        env.block.time = start + 40;
+        let res = handle(&mut deps, env.clone(), buy.clone()).unwrap();
+        assert_eq!(&res.messages[1..], &[
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("seller"),
+                amount: coins(730, "uscrt"),
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("buyer"),
+                amount: coins(70, "uscrt"),
+            }),
+        ]);
+        // without a time the prices are those at the latest transaction
+        assert_eq!(vec![730], listings(&deps, None));
+        let res = query(&deps, QueryMsg::MyListings { permit: view_permit(), page: None, page_size: None }).unwrap();
+        let my_prices: Vec<u128> = from_binary::<ListingsResponse>(&res).unwrap().listings.iter().map(|l| l.current_price.u128()).collect();
+        assert_eq!(vec![730], my_prices);
+
+        env.block.time = start + 200;
+        env.message.sent_funds = coins(99, "uscrt");
+        assert!(handle(&mut deps, env.clone(), HandleMsg::Transfer { token_id: "2".to_string(), receipient: None }).is_err());
+        env.message.sent_funds = coins(100, "uscrt");
+        handle(&mut deps, env, HandleMsg::Transfer { token_id: "2".to_string(), receipient: None }).unwrap();
+
+        // the decay of the largest prices does not overflow
+        let dutch = format!(r#"{{"dutch_auction":{{"start_price":{{"amount":"{}","denom":"uscrt"}},"end_price":"0","start_time":{},"end_time":{}}}}}"#, u128::MAX, start + 10, start + 110);
+        list(&mut deps, "3", "seller", &dutch).unwrap();
+        assert_eq!(vec![u128::MAX / 2 + 1], listings(&deps, Some(start + 60)));
+    }
+
+    #[test]
+    fn sealed_auction() {
+        let mut deps = market_deps();
+        let end = mock_env("anyone", &[]).block.time + 100;
+        // native bids are public, so sealed auctions only take SNIP-20 bids
+        let sealed = |denom: &str| format!(r#"{{"sealed_auction":{{"reserve":{{"amount":"100","denom":"{}"}},"end_time":{}}}}}"#, denom, end);
+        assert!(list(&mut deps, "1", "seller", &sealed("uscrt")).is_err());
+        list(&mut deps, "1", "seller", &sealed(SNIP20_ADDR)).unwrap();
+        let update = HandleMsg::UpdateListing { token_id: "1".to_string(), price: Coin::new(100, "uscrt"), payout: None };
+        assert!(handle(&mut deps, mock_env("seller", &[]), update).is_err());
+        assert!(handle(&mut deps, mock_env("dave", &coins(300, SNIP20_ADDR)), HandleMsg::Bid { token_id: "1".to_string() }).is_err());
+        let bid = |bidder: &str, amount: u128| HandleMsg::Receive {
+            sender: HumanAddr::from(bidder),
+            from: HumanAddr::from(bidder),
+            amount: Uint128(amount),
+            msg: Some(to_binary(&ReceiveMsg::Bid { token_id: "1".to_string() }).unwrap()),
+        };
+
+        assert!(handle(&mut deps, mock_env(SNIP20_ADDR, &[]), bid("dave", 99)).is_err());
+        for (bidder, amount) in &[("alice", 300), ("bob", 150), ("carol", 180)] {
+            let res = handle(&mut deps, mock_env(SNIP20_ADDR, &[]), bid(bidder, *amount)).unwrap();
+            assert!(res.messages.is_empty() && res.log.is_empty());
+        }
+        let refund = |to: &str, amount| transfer_msg(HumanAddr::from(to), Uint128(amount), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(SNIP20_ADDR)).unwrap();
+        // rebidding refunds the earlier bid
+        let res = handle(&mut deps, mock_env(SNIP20_ADDR, &[]), bid("bob", 200)).unwrap();
+        assert_eq!(res.messages, vec![refund("bob", 150)]);
+        let res = query(&deps, QueryMsg::Listings { start_after: None, limit: None, time: None }).unwrap();
+        let listing = &from_binary::<ListingsResponse>(&res).unwrap().listings[0];
+        assert_eq!((Uint128(100), &SaleKind::Sealed { end_time: end }), (listing.current_price, &listing.store_info.kind));
+
+        assert!(handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "1".to_string() }).is_err());
+        let finalize = HandleMsg::Finalize { token_id: "1".to_string() };
+        assert!(handle(&mut deps, mock_env("anyone", &[]), finalize.clone()).is_err());
+
+        let mut env = mock_env("anyone", &[]);
+        env.block.time = end;
+        let res = handle(&mut deps, env, finalize).unwrap();
+        assert_eq!(res.messages, vec![
+            transfer_nft_msg(HumanAddr::from("alice"), "1".to_string(), None, None, 256,
+                             IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            refund("seller", 200),
+            refund("alice", 100),
+            refund("bob", 200),
+            refund("carol", 180),
+        ]);
+        assert!(sealed_bids_read(&deps.storage, "1").unwrap().is_empty());
+        assert_eq!(Uint128(200), sales_read(&deps.storage, &SaleFilter::Purchases, &HumanAddr::from("alice"), 0, 10).unwrap().0[0].price);
+    }
+
+    #[test]
+    fn offers() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        deps.querier.ed_dossiers.insert("2".to_string(), dossier("holder"));
+        let offer = |token_id: &str| HandleMsg::MakeOffer { token_id: token_id.to_string(), expires: None };
+        let refund = |to: &str, amount| CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from(to),
+            amount: coins(amount, "uscrt"),
+        });
+
+        assert!(handle(&mut deps, mock_env("alice", &[]), offer("1")).is_err());
+        assert!(handle(&mut deps, mock_env("alice", &coins(10, "uscrt")), offer("unknown")).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &coins(10, "uscrt")), offer("1")).is_err());
+        handle(&mut deps, mock_env("alice", &coins(500, "uscrt")), offer("1")).unwrap();
+        let res = handle(&mut deps, mock_env("alice", &coins(600, "uscrt")), offer("1")).unwrap();
+        assert_eq!(res.messages, vec![refund("alice", 500)]);
+        handle(&mut deps, mock_env("bob", &coins(400, "uscrt")), offer("1")).unwrap();
+        handle(&mut deps, mock_env("alice", &coins(300, "uscrt")), offer("2")).unwrap();
+        let mut env = mock_env("carol", &coins(300, "uscrt"));
+        handle(&mut deps, env.clone(), HandleMsg::MakeOffer { token_id: "2".to_string(), expires: Some(Expiration::AtHeight(env.block.height + 1)) }).unwrap();
+        assert_eq!(vec!["1", "2"], offered_tokens(&deps.storage, &HumanAddr::from("alice")).unwrap());
+
+        let res = handle(&mut deps, mock_env("bob", &[]), HandleMsg::CancelOffer { token_id: "1".to_string() }).unwrap();
+        assert_eq!(res.messages, vec![refund("bob", 400)]);
+        assert!(handle(&mut deps, mock_env("bob", &[]), HandleMsg::CancelOffer { token_id: "1".to_string() }).is_err());
+
+        let accept = HandleMsg::AcceptOffer { token_id: "1".to_string(), buyer: HumanAddr::from("alice") };
+        match handle(&mut deps, mock_env("alice", &[]), accept.clone()) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        let res = handle(&mut deps, mock_env("seller", &[]), accept).unwrap();
+        assert_eq!(res.messages, vec![
+            transfer_nft_msg(HumanAddr::from("alice"), "1".to_string(), None, None, 256,
+                             IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            refund("seller", 600),
+        ]);
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+        assert_eq!(vec!["2"], offered_tokens(&deps.storage, &HumanAddr::from("alice")).unwrap());
+
+        // an unlisted token is sent in to accept an offer on it, expired offers can not be accepted
+        let accept = |deps: &mut Extern<MockStorage, MockApi, NftQuerier>, buyer: &str, env: Env| {
+            deps.querier.ed_dossiers.insert("2".to_string(), dossier(MOCK_CONTRACT_ADDR));
+            handle(deps, env, HandleMsg::ReceiveNft {
+                sender: HumanAddr::from("holder"),
+                token_id: "2".to_string(),
+                msg: Some(Binary::from(format!(r#"{{"accept_offer":{{"buyer":"{}"}}}}"#, buyer).as_bytes())),
+            })
+        };
+        env = mock_env(ED_ADDR, &[]);
+        env.block.height += 1;
+        assert!(accept(&mut deps, "carol", env.clone()).is_err());
+        let res = accept(&mut deps, "alice", env).unwrap();
+        assert_eq!(res.messages, vec![
+            transfer_nft_msg(HumanAddr::from("alice"), "2".to_string(), None, None, 256,
+                             IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap(),
+            refund("holder", 300),
+        ]);
+        assert_eq!(HumanAddr::from("carol"), offers_read(&deps.storage, "2").unwrap()[0].buyer);
+    }
+
+    #[test]
+    fn listing_expiration() {
+        let mut deps = market_deps();
+        let mut env = mock_env("buyer", &coins(1000, "uscrt"));
+        let listing = format!(r#"{{"list":{{"price":{{"amount":"1000","denom":"uscrt"}},"expires_at":{{"at_height":{}}}}}}}"#, env.block.height + 10);
+        list(&mut deps, "1", "seller", &listing).unwrap();
+        let reclaim = HandleMsg::ReclaimExpired { token_id: "1".to_string() };
+        assert!(handle(&mut deps, mock_env("anyone", &[]), reclaim.clone()).is_err());
+
+        env.block.height += 10;
+        assert!(handle(&mut deps, env.clone(), HandleMsg::Transfer { token_id: "1".to_string(), receipient: None }).is_err());
+        env.message.sent_funds = vec![];
+        let res = handle(&mut deps, env, reclaim).unwrap();
+        assert_eq!(res.messages[1], transfer_nft_msg(HumanAddr::from("seller"), "1".to_string(), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+
+        // an expired bundled token is reclaimed out of its bundle, the other token stays listed
+        let env = mock_env("anyone", &[]);
+        let listing = format!(r#"{{"list":{{"price":{{"amount":"1000","denom":"uscrt"}},"expires_at":{{"at_height":{}}}}}}}"#, env.block.height + 10);
+        list(&mut deps, "2", "seller", &listing).unwrap();
+        list(&mut deps, "3", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let res = handle(&mut deps, mock_env("seller", &[]), HandleMsg::CreateBundle {
+            token_ids: vec!["2".to_string(), "3".to_string()],
+            price: Coin::new(1500, "uscrt"),
+            payout: None,
+        }).unwrap();
+        let bundle_id: u64 = from_binary(&res.data.unwrap()).unwrap();
+        let mut env = mock_env("anyone", &[]);
+        env.block.height += 10;
+        let res = handle(&mut deps, env, HandleMsg::ReclaimExpired { token_id: "2".to_string() }).unwrap();
+        assert_eq!(res.messages[1], transfer_nft_msg(HumanAddr::from("seller"), "2".to_string(), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+        assert!(query(&deps, QueryMsg::Bundle { bundle_id }).is_err());
+        assert_eq!(vec!["3"], listed_tokens(&deps.storage, None, 10).unwrap());
+        handle(&mut deps, mock_env("buyer", &coins(1000, "uscrt")), HandleMsg::Transfer { token_id: "3".to_string(), receipient: None }).unwrap();
+    }
+
+    #[test]
+    fn royalty_rounding() {
+        let royalty = |rates: Vec<u16>| DisplayRoyaltyInfo {
+            decimal_places_in_rates: 2,
+            royalties: rates.into_iter().map(|rate| DisplayRoyalty { recipient: Some(HumanAddr::from("creator")), rate }).collect(),
+        };
+        let payouts = royalty_payouts(&royalty(vec![33, 33]), Uint128(10)).unwrap();
+        assert_eq!(vec![Uint128(3), Uint128(3)], payouts.into_iter().map(|(_, amount)| amount).collect::<Vec<_>>());
+        assert_eq!(vec![Uint128(7)], royalty_payouts(&royalty(vec![100]), Uint128(7)).unwrap().into_iter().map(|(_, amount)| amount).collect::<Vec<_>>());
+        assert!(royalty_payouts(&royalty(vec![60, 41]), Uint128(7)).is_err());
+    }
+
+    #[test]
+    fn cancel_listing() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let cancel = HandleMsg::CancelListing { token_id: "1".to_string() };
+
+        match handle(&mut deps, mock_env("anyone", &[]), cancel.clone()) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        let res = handle(&mut deps, mock_env("seller", &[]), cancel.clone()).unwrap();
+        assert_eq!(2, res.messages.len());
+        assert_eq!(res.messages[1], transfer_nft_msg(HumanAddr::from("seller"), "1".to_string(), None, None, 256,
+                                                     IP_C_HASH.to_string(), HumanAddr::from(ED_ADDR)).unwrap());
+        assert!(store_read(&deps.storage, &"1".to_string()).is_err());
+        assert!(handle(&mut deps, mock_env("seller", &[]), cancel).is_err());
+    }
+
+    #[test]
+    fn update_listing() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"},"payout":"payout"}}"#).unwrap();
+        let update = HandleMsg::UpdateListing { token_id: "1".to_string(), price: Coin::new(20, "uatom"), payout: None };
+
+        match handle(&mut deps, mock_env("anyone", &[]), update.clone()) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        handle(&mut deps, mock_env("seller", &[]), update).unwrap();
+        let info = store_read(&deps.storage, &"1".to_string()).unwrap();
+        assert_eq!((Uint128(20), "uatom"), (info.price, info.denom.as_str()));
+        assert_eq!(HumanAddr::from("payout"), info.payout_addr());
+    }
+
+    #[test]
+    fn listings() {
+        let mut deps = market_deps();
+        for id in &["c", "a", "d", "b"] {
+            list(&mut deps, id, "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        }
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "c".to_string() }).unwrap();
+        // updating a listing keeps its place
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::UpdateListing { token_id: "a".to_string(), price: Coin::new(500, "uscrt"), payout: None }).unwrap();
+        let page = |deps: &Extern<MockStorage, MockApi, NftQuerier>, start_after: Option<&str>, limit: Option<u32>| {
+            let res = query(deps, QueryMsg::Listings { start_after: start_after.map(String::from), limit, time: None }).unwrap();
+            from_binary::<ListingsResponse>(&res).unwrap().listings
         };
-        let env = mock_env("creator", &coins(1000, "token"));
-        let _res = init(&mut deps, env, msg).unwrap();
+        let ids = |listings: Vec<Listing>| listings.into_iter().map(|l| l.token_id).collect::<Vec<_>>();
+
+        let res = page(&deps, None, Some(2));
+        assert_eq!(Uint128(500), res[0].store_info.price);
+        assert_eq!(vec!["a", "d"], ids(res));
+        assert_eq!(vec!["b"], ids(page(&deps, Some("d"), None)));
+        // a page ending with a token sold or cancelled since continues after it
+        assert_eq!(vec!["a", "d", "b"], ids(page(&deps, Some("c"), None)));
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "d".to_string() }).unwrap();
+        assert_eq!(vec!["b"], ids(page(&deps, Some("d"), None)));
+        assert_eq!(vec!["a", "b"], ids(page(&deps, Some("c"), None)));
+        assert!(query(&deps, QueryMsg::Listings { start_after: Some("z".to_string()), limit: None, time: None }).is_err());
+        list(&mut deps, "d", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+
+        // unlinking the head and the tail
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "a".to_string() }).unwrap();
+        handle(&mut deps, mock_env("seller", &[]), HandleMsg::CancelListing { token_id: "b".to_string() }).unwrap();
+        list(&mut deps, "c", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        assert_eq!(vec!["d", "c"], listed_tokens(&deps.storage, None, 10).unwrap());
+    }
+
+    #[test]
+    fn seller_index() {
+        let mut deps = market_deps();
+        for (id, seller) in &[("c", "alice"), ("a", "bob"), ("b", "alice"), ("d", "alice")] {
+            list(&mut deps, id, seller, r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        }
+        handle(&mut deps, mock_env("alice", &[]), HandleMsg::CancelListing { token_id: "b".to_string() }).unwrap();
+        // relisting by another seller moves the token between indexes
+        list(&mut deps, "a", "alice", r#"{"list":{"price":{"amount":"10","denom":"uscrt"}}}"#).unwrap();
+
+        assert_eq!(vec!["a", "c", "d"], seller_tokens(&deps.storage, &HumanAddr::from("alice")).unwrap());
+        assert!(seller_tokens(&deps.storage, &HumanAddr::from("bob")).unwrap().is_empty());
+
+        let seller = validate(&deps, PREFIX_PERMITS, &view_permit(), HumanAddr::from(MOCK_CONTRACT_ADDR), None).unwrap();
+        list(&mut deps, "e", &seller, r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let my_listings = |page: u32| query(&deps, QueryMsg::MyListings { permit: view_permit(), page: Some(page), page_size: Some(30) })
+            .map(|res| from_binary::<ListingsResponse>(&res).unwrap().listings.len());
+        assert_eq!(1, my_listings(0).unwrap());
+        assert_eq!(0, my_listings(u32::MAX).unwrap());
+    }
+
+    fn with_trait(mut nft: NftDossier, trait_type: &str, value: &str) -> NftDossier {
+        nft.public_metadata = Some(Metadata {
+            token_uri: None,
+            extension: Some(Extension {
+                attributes: Some(vec![Trait { display_type: None, trait_type: Some(trait_type.to_string()), value: value.to_string(), max_value: None }]),
+                ..Extension::default()
+            }),
+        });
+        nft
+    }
 
-        let token = HumanAddr("secret1rf03820fp8gngzg2w02vd30ns78qkc8rg8dxaq".to_string());
-        let permit: Permit = Permit{
+    fn with_agc(nft: NftDossier, agc: &str) -> NftDossier {
+        with_trait(nft, "agc", agc)
+    }
+
+    /// an IP token unlocking ED tokens whose `agc` is `agc` under the default rule
+    fn ip_license(agc: &str) -> NftDossier {
+        with_trait(dossier("holder"), agc, "license")
+    }
+
+    fn view_permit() -> Permit {
+        Permit{
             params: PermitParams {
-                allowed_tokens: vec![token.clone()],
-                permit_name: "memo_secret1rf03820fp8gngzg2w02vd30ns78qkc8rg8dxaq".to_string(),
+                allowed_tokens: vec![HumanAddr::from(MOCK_CONTRACT_ADDR)],
+                permit_name: "view".to_string(),
                 chain_id: "pulsar-2".to_string(),
                 permissions: vec![TokenPermissions::History]
             },
@@ -322,13 +2329,159 @@ mod tests {
                 },
                 signature: Binary::from_base64("hw/Mo3ZZYu1pEiDdymElFkuCuJzg9soDHw+4DxK7cL9rafiyykh7VynS+guotRAKXhfYMwCiyWmiznc6R+UlsQ==").unwrap()
             }
+        }
+    }
+
+    #[test]
+    fn view() {
+        let mut deps = market_deps();
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        let mut ed = with_agc(dossier(MOCK_CONTRACT_ADDR), "a1");
+        ed.private_metadata = Some(Metadata { token_uri: Some("ipfs://private".to_string()), extension: None });
+        deps.querier.ed_dossiers.insert("1".to_string(), ed);
+        list(&mut deps, "2", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+
+        let permit = view_permit();
+        let holder = validate(&deps, PREFIX_PERMITS, &permit, HumanAddr::from(MOCK_CONTRACT_ADDR), None).unwrap();
+        deps.querier.ip_tokens.insert(holder, vec!["ip1".to_string(), "ip2".to_string()]);
+        deps.querier.ip_dossiers.insert("ip1".to_string(), ip_license("b2"));
+        let view = |deps: &Extern<MockStorage, MockApi, NftQuerier>, token_id: &str, permit: Option<Permit>| {
+            query(deps, QueryMsg::ViewNft { token_id: token_id.to_string(), permit, time: None })
+                .map(|res| from_binary::<NftResponse>(&res).unwrap())
+        };
+
+        // without a permit only the public data is shown
+        let res = view(&deps, "1", None).unwrap();
+        assert_eq!(None, res.dossier.private_metadata);
+        assert!(res.dossier.public_metadata.is_some());
+        match view(&deps, "1", Some(permit.clone())) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        // by default an IP token needs a trait named after the ED agc value, an agc trait of the same
+        // value only counts once the owner opts into MatchTrait
+        deps.querier.ip_dossiers.insert("ip2".to_string(), with_agc(dossier("holder"), "a1"));
+        assert!(view(&deps, "1", Some(permit.clone())).is_err());
+        let match_trait = GatingRule::MatchTrait { ed_trait: "agc".to_string(), ip_trait: "agc".to_string() };
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetGatingRule { rule: match_trait }).unwrap();
+        assert!(view(&deps, "1", Some(permit.clone())).is_ok());
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetGatingRule { rule: GatingRule::default() }).unwrap();
+
+        deps.querier.ip_dossiers.insert("ip2".to_string(), ip_license("a1"));
+        let res = view(&deps, "1", Some(permit.clone())).unwrap();
+        assert_eq!(Some("ipfs://private".to_string()), res.dossier.private_metadata.unwrap().token_uri);
+        assert_eq!(Uint128(1000), res.current_price);
+        // an ED token without agc trait can not be unlocked
+        match view(&deps, "2", Some(permit.clone())) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+
+        let set_rule = HandleMsg::SetGatingRule { rule: GatingRule::TokenId { token_id: "ip2".to_string() } };
+        match handle(&mut deps, mock_env("anyone", &[]), set_rule.clone()) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        handle(&mut deps, mock_env("creator", &[]), set_rule).unwrap();
+        assert!(view(&deps, "2", Some(permit.clone())).is_ok());
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetGatingRule { rule: GatingRule::MinTokens { count: 3 } }).unwrap();
+        assert!(view(&deps, "1", Some(permit)).is_err());
+    }
+
+    #[test]
+    fn access_grants() {
+        let mut deps = market_deps();
+        let mut ed = with_agc(dossier(MOCK_CONTRACT_ADDR), "a1");
+        ed.private_metadata = Some(Metadata { token_uri: Some("ipfs://private".to_string()), extension: None });
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        deps.querier.ed_dossiers.insert("1".to_string(), ed);
+        let permit = view_permit();
+        let holder = validate(&deps, PREFIX_PERMITS, &permit, HumanAddr::from(MOCK_CONTRACT_ADDR), None).unwrap();
+        let view = |deps: &Extern<MockStorage, MockApi, NftQuerier>, time: Option<u64>| {
+            query(deps, QueryMsg::ViewNft { token_id: "1".to_string(), permit: Some(view_permit()), time })
         };
-        let env = mock_env("anyone", &coins(2, "token"));
+        let claim = HandleMsg::ClaimAccess { token_id: "1".to_string() };
+
+        // a denied claim records nothing
+        let env = mock_env(holder.as_str(), &[]);
+        let now = env.block.time;
+        let res = handle(&mut deps, env.clone(), claim.clone()).unwrap();
+        assert_eq!(res.log[2].key, "denied");
+        assert!(grant_read(&deps.storage, &HumanAddr::from(holder.as_str()), "1").unwrap().is_none());
+
+        deps.querier.ip_tokens.insert(holder.clone(), vec!["ip1".to_string()]);
+        deps.querier.ip_dossiers.insert("ip1".to_string(), ip_license("a1"));
+        let res = handle(&mut deps, env.clone(), claim.clone()).unwrap();
+        assert_eq!(res.log[2].value, (now + ACCESS_GRANT_SECS).to_string());
+
+        // the grant answers without the IP contract until it expires
+        deps.querier.ip_tokens.clear();
+        assert!(view(&deps, None).is_ok());
+        assert!(view(&deps, Some(now + 10)).is_ok());
+        assert!(view(&deps, Some(now + ACCESS_GRANT_SECS)).is_err());
+        // and while the gating rule it was checked against is in force
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetGatingRule { rule: GatingRule::MinTokens { count: 1 } }).unwrap();
+        assert!(view(&deps, None).is_err());
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetGatingRule { rule: GatingRule::default() }).unwrap();
+        assert!(view(&deps, None).is_ok());
+
+        // once a transaction passed the expiry an old time does not revive the grant
+        let mut later = mock_env("anyone", &[]);
+        later.block.time = now + ACCESS_GRANT_SECS;
+        handle(&mut deps, later.clone(), claim.clone()).unwrap();
+        assert!(view(&deps, Some(0)).is_err());
+        assert!(view(&deps, Some(now)).is_err());
+        assert!(view(&deps, None).is_err());
+
+        // a failed re-verification invalidates the grant
+        later.message.sender = HumanAddr::from(holder.as_str());
+        deps.querier.ip_tokens.insert(holder.clone(), vec!["ip1".to_string()]);
+        handle(&mut deps, later.clone(), claim.clone()).unwrap();
+        deps.querier.ip_tokens.clear();
+        assert!(view(&deps, None).is_ok());
+        handle(&mut deps, later, claim).unwrap();
+        assert!(view(&deps, None).is_err());
+    }
+
+    #[test]
+    fn ip_scan_pages() {
+        let mut deps = market_deps();
+        let mut ed = with_agc(dossier(MOCK_CONTRACT_ADDR), "a1");
+        ed.private_metadata = Some(Metadata { token_uri: Some("ipfs://private".to_string()), extension: None });
+        list(&mut deps, "1", "seller", r#"{"list":{"price":{"amount":"1000","denom":"uscrt"}}}"#).unwrap();
+        deps.querier.ed_dossiers.insert("1".to_string(), ed);
+        let state = config_read(&deps.storage).load().unwrap();
+        let holder = HumanAddr::from("holder");
 
-        let msg = QueryMsg::ViewNft { token_id: "0".to_string(), permit: Option::from(permit) };
-        let res = query(&mut deps, msg).unwrap();
+        // ids sort as strings, the match is past the first page
+        let ids: Vec<String> = (0..250).map(|i| format!("ip{:03}", i)).collect();
+        deps.querier.ip_tokens.insert(holder.to_string(), ids);
+        deps.querier.ip_dossiers.insert("ip230".to_string(), ip_license("a1"));
+        let ednft = get_ed_nft(&deps, "1".to_string(), &state).unwrap();
+        assert!(ip_access(&deps, &state, holder.clone(), &ednft).unwrap());
+        // one batch query per page of 100 tokens
+        assert_eq!(deps.querier.ip_dossier_queries.get(), 3);
+        deps.querier.ip_batch = false;
+        deps.querier.ip_dossier_queries.set(0);
+        // without batch support every token of the pages scanned is queried on its own
+        assert!(ip_access(&deps, &state, holder.clone(), &ednft).unwrap());
+        assert_eq!(deps.querier.ip_dossier_queries.get(), 250);
+        deps.querier.ip_batch = true;
+        deps.querier.ip_dossiers.clear();
+        assert!(!ip_access(&deps, &state, holder.clone(), &ednft).unwrap());
 
-        let value: NftDossier = from_binary(&res).unwrap();
+        match handle(&mut deps, mock_env("anyone", &[]), HandleMsg::SetScanLimit { limit: 200 }) {
+            Err(StdError::Unauthorized { .. }) => {}
+            _ => panic!("Must return unauthorized error"),
+        }
+        assert!(handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetScanLimit { limit: 0 }).is_err());
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetScanLimit { limit: 250 }).unwrap();
+        let state = config_read(&deps.storage).load().unwrap();
+        assert!(!ip_access(&deps, &state, holder.clone(), &ednft).unwrap());
+        handle(&mut deps, mock_env("creator", &[]), HandleMsg::SetScanLimit { limit: 200 }).unwrap();
+        let state = config_read(&deps.storage).load().unwrap();
+        let err = ip_access(&deps, &state, holder, &ednft).unwrap_err();
+        assert!(err.to_string().contains("scan limit"));
     }
     //
     // #[test]
@@ -0,0 +1,208 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use secret_toolkit::snip721::{Metadata, Trait};
+
+/// decides whether the IP NFTs of a viewer unlock the private metadata of an ED token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GatingRule {
+    /// an IP token has a trait named after the value of the ED token's trait `ed_trait`
+    TraitTypeIsEdValue { ed_trait: String },
+    /// an IP token has the trait `ip_trait` with the value of the ED token's trait `ed_trait`
+    MatchTrait { ed_trait: String, ip_trait: String },
+    /// an IP token has at least one of `traits`
+    AnyTrait { traits: Vec<TraitMatch> },
+    /// an IP token has all of `traits`
+    AllTraits { traits: Vec<TraitMatch> },
+    /// the viewer holds at least `count` IP tokens
+    MinTokens { count: u32 },
+    /// the viewer holds the IP token `token_id`
+    TokenId { token_id: String },
+}
+
+/// the original rule, an IP token needs a trait named after the ED token's `agc` value
+impl Default for GatingRule {
+    fn default() -> Self {
+        GatingRule::TraitTypeIsEdValue { ed_trait: "agc".to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TraitMatch {
+    pub trait_type: String,
+    pub value: String,
+}
+
+/// an IP token of the viewer, `metadata` being its public metadata
+pub struct IpToken {
+    pub token_id: String,
+    pub metadata: Option<Metadata>,
+}
+
+impl GatingRule {
+    /// whether the rule looks at the metadata of the IP tokens, the others only need their ids
+    pub fn needs_metadata(&self) -> bool {
+        !matches!(self, GatingRule::MinTokens { .. } | GatingRule::TokenId { .. })
+    }
+
+    /// evaluates the rule for an ED token with public metadata `ed` and the IP tokens of the viewer
+    pub fn is_satisfied(&self, ed: Option<&Metadata>, ip_tokens: &[IpToken]) -> bool {
+        let mut evaluator = Evaluator::new(self, ed);
+        evaluator.is_satisfied() || ip_tokens.iter().any(|ip| evaluator.add(ip))
+    }
+}
+
+/// evaluates a rule over IP tokens fed one at a time, so a scan can stop at the first match
+pub struct Evaluator<'a> {
+    rule: &'a GatingRule,
+    ed: Option<&'a Metadata>,
+    seen: u32,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(rule: &'a GatingRule, ed: Option<&'a Metadata>) -> Self {
+        Evaluator { rule, ed, seen: 0 }
+    }
+
+    /// whether the tokens added so far satisfy the rule
+    pub fn is_satisfied(&self) -> bool {
+        matches!(self.rule, GatingRule::MinTokens { count } if self.seen >= *count)
+    }
+
+    /// adds the next IP token of the viewer, returning whether the rule is satisfied now
+    pub fn add(&mut self, ip: &IpToken) -> bool {
+        self.seen += 1;
+        let metadata = ip.metadata.as_ref();
+        match self.rule {
+            GatingRule::TraitTypeIsEdValue { ed_trait } => match trait_value(self.ed, ed_trait) {
+                Some(value) => attributes(metadata).iter().any(|t| t.trait_type.as_deref() == Some(value)),
+                None => false,
+            },
+            GatingRule::MatchTrait { ed_trait, ip_trait } => match trait_value(self.ed, ed_trait) {
+                Some(value) => has_trait(metadata, ip_trait, value),
+                None => false,
+            },
+            GatingRule::AnyTrait { traits } => traits.iter().any(|t| has_trait(metadata, &t.trait_type, &t.value)),
+            GatingRule::AllTraits { traits } => {
+                !traits.is_empty() && traits.iter().all(|t| has_trait(metadata, &t.trait_type, &t.value))
+            }
+            GatingRule::MinTokens { .. } => self.is_satisfied(),
+            GatingRule::TokenId { token_id } => ip.token_id == *token_id,
+        }
+    }
+}
+
+fn attributes(metadata: Option<&Metadata>) -> &[Trait] {
+    metadata
+        .and_then(|m| m.extension.as_ref())
+        .and_then(|e| e.attributes.as_deref())
+        .unwrap_or(&[])
+}
+
+fn trait_value<'a>(metadata: Option<&'a Metadata>, trait_type: &str) -> Option<&'a str> {
+    attributes(metadata).iter()
+        .find(|t| t.trait_type.as_deref() == Some(trait_type))
+        .map(|t| t.value.as_str())
+}
+
+fn has_trait(metadata: Option<&Metadata>, trait_type: &str, value: &str) -> bool {
+    attributes(metadata).iter()
+        .any(|t| t.trait_type.as_deref() == Some(trait_type) && t.value == value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secret_toolkit::snip721::Extension;
+
+    fn metadata(traits: &[(&str, &str)]) -> Option<Metadata> {
+        Some(Metadata {
+            token_uri: None,
+            extension: Some(Extension {
+                attributes: Some(traits.iter().map(|(trait_type, value)| Trait {
+                    display_type: None,
+                    trait_type: Some(trait_type.to_string()),
+                    value: value.to_string(),
+                    max_value: None,
+                }).collect()),
+                ..Extension::default()
+            }),
+        })
+    }
+
+    fn ip(token_id: &str, traits: &[(&str, &str)]) -> IpToken {
+        IpToken { token_id: token_id.to_string(), metadata: metadata(traits) }
+    }
+
+    fn trait_match(trait_type: &str, value: &str) -> TraitMatch {
+        TraitMatch { trait_type: trait_type.to_string(), value: value.to_string() }
+    }
+
+    #[test]
+    fn match_trait() {
+        let rule = GatingRule::MatchTrait { ed_trait: "agc".to_string(), ip_trait: "agc".to_string() };
+        let ed = metadata(&[("agc", "a1")]);
+        assert!(rule.is_satisfied(ed.as_ref(), &[ip("1", &[("agc", "b2")]), ip("2", &[("agc", "a1")])]));
+        assert!(!rule.is_satisfied(ed.as_ref(), &[ip("1", &[("agc", "b2")]), ip("2", &[("other", "a1")])]));
+        // an ED token without the trait can not be unlocked
+        assert!(!rule.is_satisfied(metadata(&[("other", "a1")]).as_ref(), &[ip("1", &[("agc", "a1")])]));
+        assert!(!rule.is_satisfied(None, &[ip("1", &[])]));
+
+        let rule = GatingRule::MatchTrait { ed_trait: "series".to_string(), ip_trait: "license".to_string() };
+        assert!(rule.is_satisfied(metadata(&[("series", "s1")]).as_ref(), &[ip("1", &[("license", "s1")])]));
+    }
+
+    #[test]
+    fn trait_type_is_ed_value() {
+        let rule = GatingRule::TraitTypeIsEdValue { ed_trait: "agc".to_string() };
+        let ed = metadata(&[("agc", "a1")]);
+        assert!(rule.is_satisfied(ed.as_ref(), &[ip("1", &[("b2", "x")]), ip("2", &[("a1", "any")])]));
+        // the value of the IP trait does not matter, its name does
+        assert!(!rule.is_satisfied(ed.as_ref(), &[ip("1", &[("agc", "a1")])]));
+        assert!(!rule.is_satisfied(None, &[ip("1", &[("a1", "any")])]));
+        assert!(rule.needs_metadata());
+    }
+
+    #[test]
+    fn any_and_all_traits() {
+        let traits = vec![trait_match("tier", "gold"), trait_match("region", "eu")];
+        let any = GatingRule::AnyTrait { traits: traits.clone() };
+        let all = GatingRule::AllTraits { traits };
+        let partial = [ip("1", &[("tier", "gold")]), ip("2", &[("region", "eu")])];
+        let full = [ip("1", &[("region", "eu"), ("tier", "gold")])];
+
+        assert!(any.is_satisfied(None, &partial));
+        assert!(!all.is_satisfied(None, &partial));
+        assert!(all.is_satisfied(None, &full));
+        assert!(!any.is_satisfied(None, &[ip("1", &[("tier", "silver")])]));
+        assert!(!GatingRule::AllTraits { traits: vec![] }.is_satisfied(None, &full));
+    }
+
+    #[test]
+    fn evaluator_stops_at_first_match() {
+        let rule = GatingRule::MinTokens { count: 2 };
+        let mut evaluator = Evaluator::new(&rule, None);
+        assert!(!evaluator.add(&ip("1", &[])));
+        assert!(evaluator.add(&ip("2", &[])));
+
+        let rule = GatingRule::default();
+        let ed = metadata(&[("agc", "a1")]);
+        let mut evaluator = Evaluator::new(&rule, ed.as_ref());
+        assert!(!evaluator.is_satisfied());
+        assert!(!evaluator.add(&ip("1", &[("b2", "x")])));
+        assert!(evaluator.add(&ip("2", &[("a1", "x")])));
+    }
+
+    #[test]
+    fn token_rules() {
+        let tokens = [ip("1", &[]), ip("2", &[])];
+        assert!(GatingRule::MinTokens { count: 2 }.is_satisfied(None, &tokens));
+        assert!(!GatingRule::MinTokens { count: 3 }.is_satisfied(None, &tokens));
+        assert!(GatingRule::MinTokens { count: 0 }.is_satisfied(None, &[]));
+        assert!(GatingRule::TokenId { token_id: "2".to_string() }.is_satisfied(None, &tokens));
+        assert!(!GatingRule::TokenId { token_id: "3".to_string() }.is_satisfied(None, &tokens));
+        assert!(!GatingRule::MinTokens { count: 1 }.needs_metadata());
+        assert!(GatingRule::default().needs_metadata());
+    }
+}
@@ -1,9 +1,11 @@
-use cosmwasm_std::{Binary, HumanAddr};
+use cosmwasm_std::{Binary, Coin, HumanAddr, StdError, StdResult, Uint128};
 use schemars::JsonSchema;
 use secret_toolkit::permit::Permit;
-use secret_toolkit::snip721::{Expiration, Metadata, NftDossier, Snip721Approval};
+use secret_toolkit::snip721::{Expiration, Metadata, NftDossier, ViewerInfo};
+use secret_toolkit::utils::Query;
 use serde::{Deserialize, Serialize};
-use crate::state::StoreNftInfo;
+use crate::gating::GatingRule;
+use crate::state::{Offer, SaleRecord, Snip20Token, StoreNftInfo};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InitMsg {
@@ -11,7 +13,15 @@ pub struct InitMsg {
     pub ed_code_hash: String,
     pub ip_ctr: HumanAddr,
     pub ip_code_hash: String,
-    pub view_key: String
+    pub view_key: String,
+    /// SNIP-20 tokens listings can be priced in
+    pub payment_tokens: Option<Vec<Snip20Token>>,
+    /// platform fee in basis points, none if omitted
+    pub fee_bps: Option<u16>,
+    /// receives the platform fees, the instantiator if omitted
+    pub treasury: Option<HumanAddr>,
+    /// an IP trait named after the ED token's `agc` value if omitted
+    pub gating_rule: Option<GatingRule>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -22,11 +32,180 @@ pub enum HandleMsg {
         token_id: String,
         msg: Option<Binary>,
     },
+    /// SNIP-721 callback for a batch send, lists every token for `from` with the `ListingMsg` in `msg`.
+    /// Contracts registered for batches send single tokens here as well, which are handled like ReceiveNft
+    BatchReceiveNft {
+        sender: HumanAddr,
+        from: HumanAddr,
+        token_ids: Vec<String>,
+        msg: Option<Binary>,
+    },
     Reset {
         view_key: String},
     Transfer {
         token_id:String,
-        receipient: Option<HumanAddr>}
+        receipient: Option<HumanAddr>},
+    /// buys all `token_ids` at once, paying their summed prices with the sent funds
+    BatchTransfer {
+        token_ids: Vec<String>,
+        recipient: Option<HumanAddr>},
+    /// returns an escrowed token to its seller
+    CancelListing {
+        token_id: String},
+    /// reprices an escrowed token, keeping the current payout address if none is given
+    UpdateListing {
+        token_id: String,
+        price: Coin,
+        payout: Option<HumanAddr>},
+    /// SNIP-20 Send callback, `msg` being a `ReceiveMsg`
+    Receive {
+        sender: HumanAddr,
+        from: HumanAddr,
+        amount: Uint128,
+        msg: Option<Binary>},
+    /// whitelists a SNIP-20 token for payments
+    AddPaymentToken {
+        token: Snip20Token},
+    SetFee {
+        fee_bps: u16,
+        treasury: Option<HumanAddr>},
+    /// sends the accumulated platform fees to the treasury
+    WithdrawFees {},
+    SetGatingRule {
+        rule: GatingRule},
+    /// caps the IP tokens of a viewer the gating rule examines, bounding the gas of ViewNft
+    SetScanLimit {
+        limit: u32},
+    /// checks the IP NFTs of the sender against the gating rule once and records a grant ViewNft
+    /// answers from until it expires. A failed check removes an earlier grant
+    ClaimAccess {
+        token_id: String},
+    /// bids the sent funds on an English auction, refunding the outbid bidder
+    Bid {
+        token_id: String},
+    /// settles an auction once it ended, anyone may call it
+    Finalize {
+        token_id: String},
+    /// offers the sent funds for a listed or unlisted token, replacing an earlier offer of the sender
+    MakeOffer {
+        token_id: String,
+        expires: Option<Expiration>},
+    /// sells listed tokens of the sender together for one price, they can no longer be bought alone
+    CreateBundle {
+        token_ids: Vec<String>,
+        price: Coin,
+        payout: Option<HumanAddr>},
+    /// ends a bundle, its tokens stay listed on their own
+    CancelBundle {
+        bundle_id: u64},
+    /// buys all tokens of a bundle with the sent funds
+    BuyBundle {
+        bundle_id: u64,
+        recipient: Option<HumanAddr>},
+    /// returns a token whose listing expired to its seller, anyone may call it. A bundle of the token is cancelled
+    ReclaimExpired {
+        token_id: String},
+    /// refunds the sender's offer
+    CancelOffer {
+        token_id: String},
+    /// sells a listed token to `buyer` for their offer, unlisted tokens are
+    /// sent in with a `ListingMsg::AcceptOffer` instead
+    AcceptOffer {
+        token_id: String,
+        buyer: HumanAddr},
+}
+
+/// json carried in the `msg` of a SNIP-20 Send to this contract
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    /// buys a listing priced in the sent token
+    Buy {
+        token_id: String,
+        recipient: Option<HumanAddr>},
+    /// bids the sent tokens on an auction priced in the sent token
+    Bid {
+        token_id: String},
+    /// buys a bundle priced in the sent token
+    BuyBundle {
+        bundle_id: u64,
+        recipient: Option<HumanAddr>},
+}
+
+/// json carried in the `msg` of the SNIP-721 SendNft that deposits a token,
+/// new listing formats are added as new variants
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ListingMsg {
+    /// fixed price listing
+    List {
+        price: Coin,
+        /// address receiving the proceeds, the sender if omitted
+        payout: Option<HumanAddr>,
+        expires_at: Option<Expiration>,
+    },
+    /// English auction, sold to the highest bid of at least `reserve` once `end_time` passed
+    Auction {
+        reserve: Coin,
+        min_increment: Uint128,
+        /// block time in seconds
+        end_time: u64,
+        /// address receiving the proceeds, the sender if omitted
+        payout: Option<HumanAddr>,
+    },
+    /// sealed-bid auction, the highest bid of at least `reserve` wins once `end_time` passed
+    /// and pays the second highest bid, or `reserve` if it is the only one. Priced in a whitelisted
+    /// SNIP-20 token, whose transfers keep the bids private
+    SealedAuction {
+        reserve: Coin,
+        /// block time in seconds
+        end_time: u64,
+        /// address receiving the proceeds, the sender if omitted
+        payout: Option<HumanAddr>,
+    },
+    /// fixed price listings of a batch send, each token at its own price
+    ListEach {
+        prices: Vec<TokenPrice>,
+        /// address receiving the proceeds, the sender if omitted
+        payout: Option<HumanAddr>,
+        expires_at: Option<Expiration>,
+    },
+    /// sells the token for the offer of `buyer` instead of listing it
+    AcceptOffer {
+        buyer: HumanAddr,
+    },
+    /// Dutch auction, its price decays linearly from `start_price` to `end_price` between
+    /// `start_time` and `end_time` and stays at `end_price` afterwards
+    DutchAuction {
+        start_price: Coin,
+        end_price: Uint128,
+        /// block time in seconds
+        start_time: u64,
+        /// block time in seconds
+        end_time: u64,
+        /// address receiving the proceeds, the sender if omitted
+        payout: Option<HumanAddr>,
+    },
+}
+
+impl ListingMsg {
+    /// the listing of `token_id`, picking its price out of a `ListEach`
+    pub fn for_token(&self, token_id: &str) -> StdResult<ListingMsg> {
+        match self {
+            ListingMsg::ListEach { prices, payout, expires_at } => {
+                let price=prices.iter().find(|p| p.token_id==token_id)
+                    .ok_or_else(|| StdError::generic_err(format!("no price for token {}", token_id)))?;
+                Ok(ListingMsg::List { price: price.price.clone(), payout: payout.clone(), expires_at: *expires_at })
+            }
+            listing => Ok(listing.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenPrice {
+    pub token_id: String,
+    pub price: Coin,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -36,7 +215,55 @@ pub enum QueryMsg {
     GetConfig {permit:Option<Permit>},
     ViewNft {
         token_id: String,
-        permit:Option<Permit>}
+        permit:Option<Permit>,
+        /// block time in seconds the current price and access grants are judged at, queries do not know it.
+        /// The latest transaction's block time is used if it is later
+        time: Option<u64>},
+    /// listed tokens in the order they were listed
+    Listings {
+        start_after: Option<String>,
+        limit: Option<u32>,
+        /// block time in seconds the current prices are reported at, the latest transaction's block time if it is later
+        time: Option<u64>},
+    /// tokens listed by the permit signer, priced at the latest transaction's block time
+    MyListings {
+        permit: Permit,
+        page: Option<u32>,
+        page_size: Option<u32>},
+    /// completed sales of the permit signer, newest first
+    SaleHistory {
+        permit: Permit,
+        filter: SaleFilter,
+        page: Option<u32>,
+        page_size: Option<u32>},
+    /// offers of the permit signer
+    OffersMade {
+        permit: Permit,
+        page: Option<u32>,
+        page_size: Option<u32>},
+    /// offers on the tokens the permit signer has listed, offers on tokens
+    /// held outside this contract can not be attributed to their owner
+    OffersReceived {
+        permit: Permit,
+        page: Option<u32>,
+        page_size: Option<u32>},
+    Bundle {
+        bundle_id: u64},
+    /// sealed bid of the permit signer on `token_id`
+    MyBid {
+        permit: Permit,
+        token_id: String},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SaleFilter {
+    /// tokens bought by the signer
+    Purchases,
+    /// tokens sold by the signer
+    Sales,
+    /// every sale, only for the contract owner
+    All,
 }
 
 // We define a custom struct for each query response
@@ -48,10 +275,110 @@ pub struct ConfigResponse {
     pub ip_code_hash: String,
     pub owner: HumanAddr,
     pub view_key: Option<String>,
+    pub fee_bps: u16,
+    pub treasury: HumanAddr,
+    pub accrued_fees: Vec<Coin>,
+    pub gating_rule: GatingRule,
+    pub ip_scan_limit: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct NftResponse {
     pub dossier: NftDossier,
-    pub store_info: StoreNftInfo
+    pub store_info: StoreNftInfo,
+    /// price a buy pays at the queried time
+    pub current_price: Uint128
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Listing {
+    pub token_id: String,
+    pub store_info: StoreNftInfo,
+    /// price a buy pays at the queried time
+    pub current_price: Uint128
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ListingsResponse {
+    pub listings: Vec<Listing>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SaleHistoryResponse {
+    pub sales: Vec<SaleRecord>,
+    pub total: u32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenOffer {
+    pub token_id: String,
+    pub offer: Offer
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OffersResponse {
+    pub offers: Vec<TokenOffer>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidResponse {
+    pub amount: Option<Uint128>
+}
+
+/// SNIP-721 queries sent to the ED and IP contracts that secret_toolkit does not provide
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Snip721QueryMsg {
+    RoyaltyInfo {
+        token_id: Option<String>,
+        viewer: Option<ViewerInfo>},
+    BatchNftDossier {
+        token_ids: Vec<String>,
+        viewer: Option<ViewerInfo>,
+        include_expired: Option<bool>},
+}
+
+impl Query for Snip721QueryMsg {
+    const BLOCK_SIZE: usize = 256;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchNftDossierResponse {
+    pub batch_nft_dossier: BatchNftDossier
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchNftDossier {
+    pub nft_dossiers: Vec<BatchNftDossierElement>
+}
+
+/// the part of a batch dossier the gating rule reads, the other fields are ignored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BatchNftDossierElement {
+    pub token_id: String,
+    pub public_metadata: Option<Metadata>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RoyaltyInfoResponse {
+    pub royalty_info: RoyaltyInfo
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RoyaltyInfo {
+    pub royalty_info: Option<DisplayRoyaltyInfo>
+}
+
+/// royalties of a token, a rate of 250 with 4 decimal places being 2.5%
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DisplayRoyaltyInfo {
+    pub decimal_places_in_rates: u8,
+    pub royalties: Vec<DisplayRoyalty>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DisplayRoyalty {
+    /// hidden if the querier may not see it
+    pub recipient: Option<HumanAddr>,
+    pub rate: u16
 }
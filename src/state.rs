@@ -1,13 +1,32 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Binary, CanonicalAddr, HumanAddr, ReadonlyStorage, StdError, StdResult, Storage};
+use cosmwasm_std::{from_binary, Binary, BlockInfo, CanonicalAddr, Coin, HumanAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128};
 
 use cosmwasm_storage::{singleton, singleton_read, ReadonlySingleton, Singleton, PrefixedStorage, ReadonlyPrefixedStorage};
 use secret_toolkit::serialization::{Json, Serde};
+use secret_toolkit::storage::{AppendStore, AppendStoreMut};
+use secret_toolkit::snip721::Expiration;
+use crate::gating::GatingRule;
+use crate::msg::{ListingMsg, SaleFilter};
 
 pub static CONFIG_KEY: &[u8] = b"config";
 pub static STORE_KEY: &[u8] = b"store";
+pub static LISTINGS_KEY: &[u8] = b"listings";
+pub static LISTINGS_ENDS_KEY: &[u8] = b"listings_ends";
+pub static SELLER_KEY: &[u8] = b"seller";
+pub static SALES_KEY: &[u8] = b"sales";
+pub static PURCHASES_KEY: &[u8] = b"purchases";
+pub static SOLD_KEY: &[u8] = b"sold";
+pub static FEES_KEY: &[u8] = b"fees";
+pub static BIDS_KEY: &[u8] = b"bids";
+pub static OFFERS_KEY: &[u8] = b"offers";
+pub static OFFERS_MADE_KEY: &[u8] = b"offers_made";
+pub static BUNDLE_KEY: &[u8] = b"bundle";
+pub static BUNDLED_KEY: &[u8] = b"bundled";
+pub static BUNDLE_COUNT_KEY: &[u8] = b"bundle_count";
+pub static GRANTS_KEY: &[u8] = b"grants";
+pub static BLOCK_TIME_KEY: &[u8] = b"block_time";
 
 pub const PREFIX_PERMITS: &str = "revoke";
 pub const SUFFIX_ED_KEY: &str = "edkk";
@@ -21,51 +40,641 @@ pub struct State {
     pub contract_addr: HumanAddr,
     pub viewing_key: String,
     pub owner: CanonicalAddr,
+    /// SNIP-20 tokens accepted as payment, listed with the token address as denom
+    pub payment_tokens: Vec<Snip20Token>,
+    /// platform fee taken from every sale, in basis points
+    pub fee_bps: u16,
+    /// receives the accumulated fees on WithdrawFees
+    pub treasury: CanonicalAddr,
+    /// IP NFTs a viewer needs to see the private metadata of listed tokens
+    #[serde(default)]
+    pub gating_rule: GatingRule,
+    /// most IP tokens of a viewer examined by the gating rule before giving up
+    #[serde(default = "default_ip_scan_limit")]
+    pub ip_scan_limit: u32,
+}
+
+pub fn default_ip_scan_limit() -> u32 {
+    1000
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Snip20Token {
+    pub address: HumanAddr,
+    pub code_hash: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct StoreNftInfo {
+    /// seller who sent the token in, allowed to manage the listing
     pub owner: HumanAddr,
-    ///using u64 for Json::serde in here and NftResponse
-    pub price: u64,
+    pub price: Uint128,
+    /// native denom, or address of a whitelisted SNIP-20 token
+    #[serde(default = "default_denom")]
+    pub denom: String,
+    /// address receiving the proceeds, `owner` if not set
+    #[serde(default)]
+    pub payout: Option<HumanAddr>,
+    /// the listing can no longer be bought afterwards, see ReclaimExpired
+    #[serde(default)]
+    pub expires_at: Option<Expiration>,
+    #[serde(default)]
+    pub kind: SaleKind,
+}
+
+/// how a listing is sold, `price` being the reserve of English and the start price of Dutch auctions
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SaleKind {
+    #[default]
+    Fixed,
+    English {
+        min_increment: Uint128,
+        end_time: u64,
+        /// highest bid, its funds are held by this contract
+        bid: Option<Bid>,
+    },
+    Dutch {
+        end_price: Uint128,
+        start_time: u64,
+        end_time: u64,
+    },
+    /// bids are kept apart from the listing, see `sealed_bids_read`
+    Sealed {
+        end_time: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Bid {
+    pub bidder: HumanAddr,
+    pub amount: Uint128,
+}
+
+/// funds a buyer escrowed for a token, listed or not
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Offer {
+    pub buyer: HumanAddr,
+    pub amount: Uint128,
+    pub denom: String,
+    pub expires: Expiration,
+}
+
+/// access to the private metadata of an ED token a viewer proved with ClaimAccess, valid while
+/// the gating rule it was checked against is unchanged and until `expires` in seconds,
+/// compared to the block time of the latest transaction
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AccessGrant {
+    pub rule: GatingRule,
+    pub expires: u64,
+}
+
+impl AccessGrant {
+    pub fn is_valid(&self, rule: &GatingRule, time: u64) -> bool {
+        self.rule==*rule && time<self.expires
+    }
+}
+
+/// listed tokens of one seller sold together for one price
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Bundle {
+    pub seller: HumanAddr,
+    pub token_ids: Vec<String>,
+    pub price: Uint128,
+    /// native denom, or address of a whitelisted SNIP-20 token
+    pub denom: String,
+    /// address receiving the proceeds, `seller` if not set
+    pub payout: Option<HumanAddr>,
+}
+
+fn default_denom() -> String {
+    String::from("uscrt")
 }
 
 impl StoreNftInfo {
+    /// decodes the `msg` of a ReceiveNft callback, `sender` being the previous token owner.
+    /// A json `ListingMsg` is expected, the whitespace separated text format is kept as fallback
+    pub fn from_msg(msg: Option<Binary>, sender: HumanAddr) -> StdResult<StoreNftInfo> {
+        let msg_bytes = msg.ok_or_else(|| StdError::serialize_err("StoreNftInfo", "no listing msg provided"))?;
+        match from_binary::<ListingMsg>(&msg_bytes) {
+            Ok(listing) => StoreNftInfo::from_listing(listing, sender),
+            // looks like json, so report why it is not a valid ListingMsg instead of the legacy error
+            Err(e) if String::from_utf8_lossy(msg_bytes.as_slice()).trim_start().starts_with('{') => Err(e),
+            Err(_) => StoreNftInfo::from(msg_bytes, sender),
+        }
+    }
+
+    pub fn from_listing(listing: ListingMsg, sender: HumanAddr) -> StdResult<StoreNftInfo> {
+        match listing {
+            ListingMsg::List { price, payout, expires_at } => Ok(StoreNftInfo {
+                owner: sender,
+                price: validate_price(&price)?,
+                denom: price.denom,
+                payout,
+                expires_at,
+                kind: SaleKind::Fixed,
+            }),
+            ListingMsg::Auction { reserve, min_increment, end_time, payout } => {
+                if min_increment.is_zero() {
+                    return Err(StdError::serialize_err("StoreNftInfo", "min_increment must be positive"));
+                }
+                Ok(StoreNftInfo {
+                    owner: sender,
+                    price: validate_price(&reserve)?,
+                    denom: reserve.denom,
+                    payout,
+                    expires_at: None,
+                    kind: SaleKind::English { min_increment, end_time, bid: None },
+                })
+            }
+            ListingMsg::SealedAuction { reserve, end_time, payout } => Ok(StoreNftInfo {
+                owner: sender,
+                price: validate_price(&reserve)?,
+                denom: reserve.denom,
+                payout,
+                expires_at: None,
+                kind: SaleKind::Sealed { end_time },
+            }),
+            ListingMsg::AcceptOffer { .. } => Err(StdError::serialize_err("StoreNftInfo", "accept_offer does not list the token")),
+            ListingMsg::ListEach { .. } => Err(StdError::serialize_err("StoreNftInfo", "list_each is only accepted with a batch send")),
+            ListingMsg::DutchAuction { start_price, end_price, start_time, end_time, payout } => {
+                if end_time<=start_time {
+                    return Err(StdError::serialize_err("StoreNftInfo", "end_time must be after start_time"));
+                }
+                if end_price>start_price.amount {
+                    return Err(StdError::serialize_err("StoreNftInfo", "end_price can not exceed start_price"));
+                }
+                Ok(StoreNftInfo {
+                    owner: sender,
+                    price: validate_price(&start_price)?,
+                    denom: start_price.denom,
+                    payout,
+                    expires_at: None,
+                    kind: SaleKind::Dutch { end_price, start_time, end_time },
+                })
+            }
+        }
+    }
+
     //msg_bytes:"price  owner  ..."
     // e.g. "1000 secret19kl6c3lml882eyzagf6z0sh7pvsj8tndcfus3k  other info"
-    pub fn from(msg_bytes:Binary) ->StdResult<StoreNftInfo>{
+    // the owner in the text only receives the proceeds, priced in uscrt
+    pub fn from(msg_bytes:Binary, sender: HumanAddr) ->StdResult<StoreNftInfo>{
         let msg=String::from_utf8(msg_bytes.into())
-            .or_else(|_e|Err(StdError::serialize_err("StoreNftInfo","invalid binary")))?;
+            .map_err(|_e|StdError::serialize_err("StoreNftInfo","invalid binary"))?;
         let mut r =msg.split_whitespace();
-        let price=r.next().ok_or_else(||StdError::serialize_err("StoreNftInfo","no price provided"))?.parse::<u64>()
-            .or_else(|_e|Err(StdError::serialize_err("StoreNftInfo","invalid price")))?;
+        let price=r.next().ok_or_else(||StdError::serialize_err("StoreNftInfo","no price provided"))?.parse::<u128>()
+            .map_err(|_e|StdError::serialize_err("StoreNftInfo","invalid price"))?;
         Ok(StoreNftInfo{
-            owner: HumanAddr::from(r.next().ok_or_else(||StdError::serialize_err("StoreNftInfo","no owner provided"))?),
-            price
+            owner: sender,
+            price: Uint128(price),
+            denom: default_denom(),
+            payout: Some(HumanAddr::from(r.next().ok_or_else(||StdError::serialize_err("StoreNftInfo","no owner provided"))?)),
+            expires_at: None,
+            kind: SaleKind::Fixed
         })
     }
+
+    /// address the sale proceeds are sent to
+    pub fn payout_addr(&self) -> HumanAddr {
+        self.payout.clone().unwrap_or_else(|| self.owner.clone())
+    }
+
+    pub fn is_expired(&self, block: &BlockInfo) -> bool {
+        self.expires_at.as_ref().is_some_and(|e| e.is_expired(block))
+    }
+
+    /// bid whose funds are escrowed for this listing
+    pub fn bid(&self) -> Option<&Bid> {
+        match &self.kind {
+            SaleKind::English { bid, .. } => bid.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// price a buy pays at block `time`, rounded up while a Dutch auction decays
+    pub fn price_at(&self, time: u64) -> StdResult<Uint128> {
+        match &self.kind {
+            SaleKind::Dutch { end_price, start_time, end_time } => {
+                if time<=*start_time { return Ok(self.price); }
+                if time>=*end_time { return Ok(*end_price); }
+                let decay=mul_ratio(Uint128(self.price.u128()-end_price.u128()), (time-start_time) as u128, (end_time-start_time) as u128)?;
+                Ok(Uint128(self.price.u128()-decay.u128()))
+            }
+            _ => Ok(self.price),
+        }
+    }
+}
+
+/// `value*num/denom` rounded down for `num<=denom`, dividing first so that large amounts
+/// do not overflow like they do in `Uint128::multiply_ratio`
+pub fn mul_ratio(value: Uint128, num: u128, denom: u128) -> StdResult<Uint128> {
+    if denom==0||num>denom {
+        return Err(StdError::generic_err(format!("invalid ratio {}/{}", num, denom)));
+    }
+    let rest=(value.u128()%denom).checked_mul(num)
+        .ok_or_else(|| StdError::generic_err(format!("{} times {}/{} overflows", value, num, denom)))?;
+    Ok(Uint128(value.u128()/denom*num+rest/denom))
+}
+
+pub fn validate_price(price: &Coin) -> StdResult<Uint128> {
+    if price.denom.is_empty() {
+        return Err(StdError::serialize_err("StoreNftInfo", "no price denom provided"));
+    }
+    Ok(price.amount)
+}
+
+/// listing as stored while prices were u64 numbers, read when an entry does not parse as
+/// `StoreNftInfo` so it is migrated to the Uint128 string encoding the next time it is saved
+#[derive(Deserialize)]
+struct LegacyStoreNftInfo {
+    owner: HumanAddr,
+    price: u64,
+    #[serde(default = "default_denom")]
+    denom: String,
+    #[serde(default)]
+    payout: Option<HumanAddr>,
+    #[serde(default)]
+    expires_at: Option<Expiration>,
+}
+
+impl From<LegacyStoreNftInfo> for StoreNftInfo {
+    fn from(legacy: LegacyStoreNftInfo) -> Self {
+        StoreNftInfo {
+            owner: legacy.owner,
+            price: Uint128::from(legacy.price),
+            denom: legacy.denom,
+            payout: legacy.payout,
+            expires_at: legacy.expires_at,
+            kind: SaleKind::Fixed,
+        }
+    }
 }
 
-pub fn config<S: Storage>(storage: &mut S) -> Singleton<S, State> {
+fn deserialize_listing(bytes: &[u8]) -> StdResult<StoreNftInfo> {
+    Json::deserialize::<StoreNftInfo>(bytes)
+        .or_else(|e| Json::deserialize::<LegacyStoreNftInfo>(bytes).map(Into::into).map_err(|_| e))
+}
+
+/// a completed purchase, kept for accounting
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SaleRecord {
+    pub token_id: String,
+    pub seller: HumanAddr,
+    pub buyer: HumanAddr,
+    pub price: Uint128,
+    pub denom: String,
+    pub block_height: u64,
+    pub block_time: u64,
+}
+
+pub fn config<S: Storage>(storage: &mut S) -> Singleton<'_, S, State> {
     singleton(storage, CONFIG_KEY)
 }
 
-pub fn config_read<S: Storage>(storage: &S) -> ReadonlySingleton<S, State> {
+pub fn config_read<S: Storage>(storage: &S) -> ReadonlySingleton<'_, S, State> {
     singleton_read(storage, CONFIG_KEY)
 }
 
 pub fn store_set<S: Storage>(storage: &mut S, token_id:&String, info: &StoreNftInfo) -> StdResult<()> {
+    listing_link(storage, token_id)?;
+    if let Some(prev)=store_may_read(storage, token_id)? {
+        seller_index_update(storage, &prev.owner, token_id, false)?;
+    }
+    seller_index_update(storage, &info.owner, token_id, true)?;
     PrefixedStorage::new(STORE_KEY, storage).set(token_id.as_bytes(),&Json::serialize(info)?);
     Ok(())
 }
-pub fn store_remove<S: Storage>(storage: &mut S,token_id:&String){
+pub fn store_remove<S: Storage>(storage: &mut S,token_id:&String) -> StdResult<()> {
+    listing_unlink(storage, token_id)?;
+    if let Some(prev)=store_may_read(storage, token_id)? {
+        seller_index_update(storage, &prev.owner, token_id, false)?;
+    }
     PrefixedStorage::new(STORE_KEY, storage).remove(token_id.as_bytes());
+    Ok(())
+}
+
+/// sealed bids on `token_id` in the order they were placed, stored next to the listings
+/// and never returned by the listing queries
+pub fn sealed_bids_read<S: ReadonlyStorage>(storage: &S, token_id: &str) -> StdResult<Vec<Bid>> {
+    ReadonlyPrefixedStorage::multilevel(&[STORE_KEY, BIDS_KEY], storage)
+        .get(token_id.as_bytes())
+        .map_or_else(|| Ok(vec![]), |bytes| Json::deserialize(&bytes))
+}
+
+pub fn sealed_bids_save<S: Storage>(storage: &mut S, token_id: &str, bids: &[Bid]) -> StdResult<()> {
+    let mut store=PrefixedStorage::multilevel(&[STORE_KEY, BIDS_KEY], storage);
+    if bids.is_empty() {
+        store.remove(token_id.as_bytes());
+    } else {
+        store.set(token_id.as_bytes(), &Json::serialize(&bids)?);
+    }
+    Ok(())
+}
+
+/// stores `bundle` under a new id and marks its tokens as bundled, returning the id
+pub fn bundle_create<S: Storage>(storage: &mut S, bundle: &Bundle) -> StdResult<u64> {
+    let id=singleton_read(storage, BUNDLE_COUNT_KEY).may_load()?.unwrap_or(0u64)+1;
+    singleton(storage, BUNDLE_COUNT_KEY).save(&id)?;
+    let mut bundled=PrefixedStorage::new(BUNDLED_KEY, storage);
+    for token_id in &bundle.token_ids {
+        bundled.set(token_id.as_bytes(), &Json::serialize(&id)?);
+    }
+    PrefixedStorage::new(BUNDLE_KEY, storage).set(&id.to_be_bytes(), &Json::serialize(bundle)?);
+    Ok(id)
+}
+
+pub fn bundle_read<S: ReadonlyStorage>(storage: &S, id: u64) -> StdResult<Bundle> {
+    ReadonlyPrefixedStorage::new(BUNDLE_KEY, storage)
+        .get(&id.to_be_bytes())
+        .map_or_else(|| Err(StdError::not_found(format!("bundle {}", id))), |bytes| Json::deserialize(&bytes))
+}
+
+pub fn bundle_remove<S: Storage>(storage: &mut S, id: u64) -> StdResult<()> {
+    let bundle=bundle_read(storage, id)?;
+    let mut bundled=PrefixedStorage::new(BUNDLED_KEY, storage);
+    for token_id in &bundle.token_ids {
+        bundled.remove(token_id.as_bytes());
+    }
+    PrefixedStorage::new(BUNDLE_KEY, storage).remove(&id.to_be_bytes());
+    Ok(())
+}
+
+/// id of the bundle `token_id` belongs to
+pub fn bundle_of<S: ReadonlyStorage>(storage: &S, token_id: &str) -> StdResult<Option<u64>> {
+    ReadonlyPrefixedStorage::new(BUNDLED_KEY, storage)
+        .get(token_id.as_bytes())
+        .map(|bytes| Json::deserialize(&bytes))
+        .transpose()
+}
+
+/// at most `limit` ids of the listed tokens following `start_after`, in the order they were listed.
+/// A `start_after` sold or cancelled since continues with the tokens that followed it
+pub fn listed_tokens<S: ReadonlyStorage>(storage: &S, start_after: Option<&str>, limit: usize) -> StdResult<Vec<String>> {
+    let mut next=match start_after {
+        Some(id) => list_node_read(storage, id)?
+            .ok_or_else(|| StdError::generic_err(format!("token {} was never listed", id)))?
+            .next,
+        None => list_ends_read(storage)?.head,
+    };
+    let mut ids=vec![];
+    while ids.len()<limit {
+        let id=match next {
+            Some(id) => id,
+            None => break,
+        };
+        let node=list_node_read(storage, &id)?.unwrap_or_default();
+        next=node.next;
+        // only the tokens following a stale `start_after` can be unlisted
+        if !node.unlisted {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+/// a listed token in the doubly linked list of listings, so listing and unlisting
+/// cost the same however many tokens are listed. An unlisted token stays behind as a
+/// tombstone keeping its `next`, so paging past it still works
+#[derive(Serialize, Deserialize, Default)]
+struct ListNode {
+    prev: Option<String>,
+    next: Option<String>,
+    #[serde(default)]
+    unlisted: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ListEnds {
+    head: Option<String>,
+    tail: Option<String>,
+}
+
+fn list_node_read<S: ReadonlyStorage>(storage: &S, token_id: &str) -> StdResult<Option<ListNode>> {
+    ReadonlyPrefixedStorage::new(LISTINGS_KEY, storage)
+        .get(token_id.as_bytes())
+        .map(|bytes| Json::deserialize(&bytes))
+        .transpose()
+}
+
+fn list_node_save<S: Storage>(storage: &mut S, token_id: &str, node: &ListNode) -> StdResult<()> {
+    PrefixedStorage::new(LISTINGS_KEY, storage).set(token_id.as_bytes(), &Json::serialize(node)?);
+    Ok(())
+}
+
+fn list_ends_read<S: ReadonlyStorage>(storage: &S) -> StdResult<ListEnds> {
+    Ok(singleton_read(storage, LISTINGS_ENDS_KEY).may_load()?.unwrap_or_default())
+}
+
+/// appends `token_id` to the listings unless it is listed already
+fn listing_link<S: Storage>(storage: &mut S, token_id: &str) -> StdResult<()> {
+    if list_node_read(storage, token_id)?.is_some_and(|node| !node.unlisted) {
+        return Ok(());
+    }
+    let mut ends=list_ends_read(storage)?;
+    match &ends.tail {
+        Some(tail) => {
+            let mut last=list_node_read(storage, tail)?.unwrap_or_default();
+            last.next=Some(token_id.to_string());
+            list_node_save(storage, tail, &last)?;
+        }
+        None => ends.head=Some(token_id.to_string()),
+    }
+    list_node_save(storage, token_id, &ListNode{ prev: ends.tail.replace(token_id.to_string()), next: None, unlisted: false })?;
+    singleton(storage, LISTINGS_ENDS_KEY).save(&ends)
+}
+
+fn listing_unlink<S: Storage>(storage: &mut S, token_id: &str) -> StdResult<()> {
+    let node=match list_node_read(storage, token_id)? {
+        Some(node) if !node.unlisted => node,
+        _ => return Ok(()),
+    };
+    let mut ends=list_ends_read(storage)?;
+    match &node.prev {
+        Some(prev) => {
+            let mut before=list_node_read(storage, prev)?.unwrap_or_default();
+            before.next=node.next.clone();
+            list_node_save(storage, prev, &before)?;
+        }
+        None => ends.head=node.next.clone(),
+    }
+    match &node.next {
+        Some(next) => {
+            let mut after=list_node_read(storage, next)?.unwrap_or_default();
+            after.prev=node.prev.clone();
+            list_node_save(storage, next, &after)?;
+        }
+        None => ends.tail=node.prev.clone(),
+    }
+    list_node_save(storage, token_id, &ListNode{ prev: None, next: node.next, unlisted: true })?;
+    singleton(storage, LISTINGS_ENDS_KEY).save(&ends)
+}
+
+/// ids of the tokens listed by `seller` in lexicographical order
+pub fn seller_tokens<S: ReadonlyStorage>(storage: &S, seller: &HumanAddr) -> StdResult<Vec<String>> {
+    index_read(storage, SELLER_KEY, seller)
+}
+
+fn seller_index_update<S: Storage>(storage: &mut S, seller: &HumanAddr, token_id: &String, listed: bool) -> StdResult<()> {
+    index_update(storage, SELLER_KEY, seller, token_id, listed)
+}
+
+/// ids of the tokens `buyer` has offers on in lexicographical order
+pub fn offered_tokens<S: ReadonlyStorage>(storage: &S, buyer: &HumanAddr) -> StdResult<Vec<String>> {
+    index_read(storage, OFFERS_MADE_KEY, buyer)
+}
+
+/// offers on `token_id` in the order they were made
+pub fn offers_read<S: ReadonlyStorage>(storage: &S, token_id: &str) -> StdResult<Vec<Offer>> {
+    ReadonlyPrefixedStorage::new(OFFERS_KEY, storage)
+        .get(token_id.as_bytes())
+        .map_or_else(|| Ok(vec![]), |bytes| Json::deserialize(&bytes))
+}
+
+/// stores `offer`, returning the earlier offer of the same buyer it replaces
+pub fn offer_set<S: Storage>(storage: &mut S, token_id: &String, offer: Offer) -> StdResult<Option<Offer>> {
+    let prev=offer_remove(storage, token_id, &offer.buyer)?;
+    index_update(storage, OFFERS_MADE_KEY, &offer.buyer, token_id, true)?;
+    let mut offers=offers_read(storage, token_id)?;
+    offers.push(offer);
+    PrefixedStorage::new(OFFERS_KEY, storage).set(token_id.as_bytes(), &Json::serialize(&offers)?);
+    Ok(prev)
+}
+
+pub fn offer_remove<S: Storage>(storage: &mut S, token_id: &String, buyer: &HumanAddr) -> StdResult<Option<Offer>> {
+    let mut offers=offers_read(storage, token_id)?;
+    let pos=match offers.iter().position(|o| o.buyer==*buyer) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let offer=offers.remove(pos);
+    index_update(storage, OFFERS_MADE_KEY, buyer, token_id, false)?;
+    let mut store=PrefixedStorage::new(OFFERS_KEY, storage);
+    if offers.is_empty() {
+        store.remove(token_id.as_bytes());
+    } else {
+        store.set(token_id.as_bytes(), &Json::serialize(&offers)?);
+    }
+    Ok(Some(offer))
+}
+
+/// block time of the latest transaction this contract handled, the clock queries can trust
+pub fn block_time_read<S: ReadonlyStorage>(storage: &S) -> StdResult<u64> {
+    Ok(singleton_read(storage, BLOCK_TIME_KEY).may_load()?.unwrap_or(0))
+}
+
+pub fn block_time_save<S: Storage>(storage: &mut S, time: u64) -> StdResult<()> {
+    singleton(storage, BLOCK_TIME_KEY).save(&time)
+}
+
+pub fn grant_read<S: ReadonlyStorage>(storage: &S, viewer: &HumanAddr, token_id: &str) -> StdResult<Option<AccessGrant>> {
+    ReadonlyPrefixedStorage::multilevel(&[GRANTS_KEY, viewer.as_str().as_bytes()], storage)
+        .get(token_id.as_bytes())
+        .map(|bytes| Json::deserialize(&bytes))
+        .transpose()
+}
+
+pub fn grant_save<S: Storage>(storage: &mut S, viewer: &HumanAddr, token_id: &str, grant: &AccessGrant) -> StdResult<()> {
+    PrefixedStorage::multilevel(&[GRANTS_KEY, viewer.as_str().as_bytes()], storage)
+        .set(token_id.as_bytes(), &Json::serialize(grant)?);
+    Ok(())
+}
+
+pub fn grant_remove<S: Storage>(storage: &mut S, viewer: &HumanAddr, token_id: &str) {
+    PrefixedStorage::multilevel(&[GRANTS_KEY, viewer.as_str().as_bytes()], storage)
+        .remove(token_id.as_bytes());
+}
+
+fn index_read<S: ReadonlyStorage>(storage: &S, key: &[u8], address: &HumanAddr) -> StdResult<Vec<String>> {
+    ReadonlyPrefixedStorage::new(key, storage)
+        .get(address.as_str().as_bytes())
+        .map_or_else(|| Ok(vec![]), |bytes| Json::deserialize(&bytes))
+}
+
+fn index_update<S: Storage>(storage: &mut S, key: &[u8], address: &HumanAddr, token_id: &String, listed: bool) -> StdResult<()> {
+    let mut ids=index_read(storage, key, address)?;
+    if sorted_update(&mut ids, token_id, listed) {
+        PrefixedStorage::new(key, storage).set(address.as_str().as_bytes(), &Json::serialize(&ids)?);
+    }
+    Ok(())
+}
+
+/// adds or removes `token_id` in the sorted `ids`, returning whether they changed
+fn sorted_update(ids: &mut Vec<String>, token_id: &String, listed: bool) -> bool {
+    match (ids.binary_search(token_id), listed) {
+        (Err(pos), true) => ids.insert(pos, token_id.clone()),
+        (Ok(pos), false) => { ids.remove(pos); }
+        _ => return false,
+    }
+    true
+}
+
+fn store_may_read<S: ReadonlyStorage>(storage: &S, token_id: &String) -> StdResult<Option<StoreNftInfo>> {
+    ReadonlyPrefixedStorage::new(STORE_KEY, storage)
+        .get(token_id.as_bytes())
+        .map(|bytes| deserialize_listing(&bytes))
+        .transpose()
 }
 
 pub fn store_read<S: Storage>(storage: &S,tokenid:&String) -> StdResult<StoreNftInfo> {
-    Json::deserialize(
+    deserialize_listing(
         &ReadonlyPrefixedStorage::new(STORE_KEY, storage)
             .get(tokenid.as_bytes())
             .ok_or_else(|| StdError::not_found(tokenid))?,
     )
 }
+
+/// fees taken from sales and not withdrawn yet, one coin per denom
+pub fn fees_read<S: ReadonlyStorage>(storage: &S) -> StdResult<Vec<Coin>> {
+    Ok(singleton_read(storage, FEES_KEY).may_load()?.unwrap_or_default())
+}
+
+pub fn fees_add<S: Storage>(storage: &mut S, denom: &str, amount: Uint128) -> StdResult<()> {
+    let mut fees=fees_read(storage)?;
+    add_coin(&mut fees, denom, amount);
+    singleton(storage, FEES_KEY).save(&fees)
+}
+
+/// adds `amount` to the coin of `denom` in `coins`, keeping one coin per denom
+pub fn add_coin(coins: &mut Vec<Coin>, denom: &str, amount: Uint128) {
+    match coins.iter_mut().find(|c| c.denom==denom) {
+        Some(coin) => coin.amount+=amount,
+        None => coins.push(Coin { denom: denom.to_string(), amount }),
+    }
+}
+
+pub fn fees_clear<S: Storage>(storage: &mut S) {
+    singleton::<S, Vec<Coin>>(storage, FEES_KEY).remove()
+}
+
+/// appends `sale` to the global log and to the buyer's and seller's logs
+pub fn record_sale<S: Storage>(storage: &mut S, sale: &SaleRecord) -> StdResult<()> {
+    append_sale(&mut PrefixedStorage::new(SALES_KEY, storage), sale)?;
+    append_sale(&mut PrefixedStorage::multilevel(&[PURCHASES_KEY, sale.buyer.as_str().as_bytes()], storage), sale)?;
+    append_sale(&mut PrefixedStorage::multilevel(&[SOLD_KEY, sale.seller.as_str().as_bytes()], storage), sale)
+}
+
+fn append_sale<S: Storage>(storage: &mut S, sale: &SaleRecord) -> StdResult<()> {
+    AppendStoreMut::attach_or_create_with_serialization(storage, Json)?.push(sale)
+}
+
+/// a page of the sales selected by `filter` for `address`, newest first, with the total count
+pub fn sales_read<S: ReadonlyStorage>(storage: &S, filter: &SaleFilter, address: &HumanAddr, page: u32, page_size: u32) -> StdResult<(Vec<SaleRecord>, u32)> {
+    match filter {
+        SaleFilter::All => sales_page(&ReadonlyPrefixedStorage::new(SALES_KEY, storage), page, page_size),
+        SaleFilter::Purchases => sales_page(&ReadonlyPrefixedStorage::multilevel(&[PURCHASES_KEY, address.as_str().as_bytes()], storage), page, page_size),
+        SaleFilter::Sales => sales_page(&ReadonlyPrefixedStorage::multilevel(&[SOLD_KEY, address.as_str().as_bytes()], storage), page, page_size),
+    }
+}
+
+fn sales_page<S: ReadonlyStorage>(storage: &S, page: u32, page_size: u32) -> StdResult<(Vec<SaleRecord>, u32)> {
+    let store = match AppendStore::<SaleRecord, _, _>::attach_with_serialization(storage, Json) {
+        Some(store) => store?,
+        None => return Ok((vec![], 0)),
+    };
+    let sales = store.iter().rev()
+        .skip(page.saturating_mul(page_size) as usize)
+        .take(page_size as usize)
+        .collect::<StdResult<Vec<SaleRecord>>>()?;
+    Ok((sales, store.len()))
+}